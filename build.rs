@@ -0,0 +1,16 @@
+use std::{env, path::PathBuf};
+
+fn main() {
+    let crate_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+
+    // Generate the header from the exported items into OUT_DIR, never into
+    // the source tree; tests/header.rs checks that the committed
+    // `include/animal_farm.h` C hosts compile against matches it.
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    cbindgen::generate(&crate_dir)
+        .expect("Unable to generate animal_farm.h")
+        .write_to_file(out_dir.join("animal_farm.h"));
+}
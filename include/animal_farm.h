@@ -0,0 +1,748 @@
+/* Generated by cbindgen from the animal_farm sources. Do not edit. */
+
+#ifndef ANIMAL_FARM_H
+#define ANIMAL_FARM_H
+
+#include <stddef.h>
+#include <stdint.h>
+#include <stdbool.h>
+
+// The latest `FunctionsMap` layout this library knows about.
+#define FUNCTIONS_MAP_VERSION 4
+
+typedef enum FarmStatus {
+  FARM_STATUS_OK = 0,
+  FARM_STATUS_NULL_POINTER,
+  FARM_STATUS_INVALID_UTF8,
+  FARM_STATUS_NOT_FOUND,
+  FARM_STATUS_ALREADY_EXISTS,
+  FARM_STATUS_INVALID_HANDLE,
+  FARM_STATUS_PANIC,
+  FARM_STATUS_BUFFER_TOO_SMALL,
+  FARM_STATUS_INVALID_ARGUMENT,
+  FARM_STATUS_NOT_SUPPORTED,
+  FARM_STATUS_LIMIT_REACHED,
+  FARM_STATUS_EXHAUSTED,
+} FarmStatus;
+
+typedef enum AddOutcome {
+  ADD_OUTCOME_ADDED = 0,
+  ADD_OUTCOME_REPLACED,
+  ADD_OUTCOME_SUFFIXED,
+} AddOutcome;
+
+// The type of an animal attribute's value.
+typedef enum AttributeKind {
+  ATTRIBUTE_KIND_INT = 0,
+  ATTRIBUTE_KIND_FLOAT,
+  ATTRIBUTE_KIND_STRING,
+  ATTRIBUTE_KIND_BOOL,
+} AttributeKind;
+
+// What `add_animal` does when the name it is given is already taken.
+typedef enum DuplicatePolicy {
+  // Fail with `AlreadyExists` and leave the farm unchanged.
+  DUPLICATE_POLICY_REJECT = 0,
+  // Store the new animal and hand the previous pointer back to the host.
+  DUPLICATE_POLICY_REPLACE,
+  // Store the new animal under `<name>#<n>`, using the lowest `n >= 2` that
+  // is free.
+  DUPLICATE_POLICY_KEEP_BOTH,
+} DuplicatePolicy;
+
+// Opaque handle to a farm, only ever used behind a pointer.
+//
+// Handle values are tokens rather than addresses: the low half of the bits
+// picks a slot in the library's farm table and the high half holds that
+// slot's generation, which changes whenever a farm is destroyed. A handle
+// that was never issued, belongs to a destroyed farm, or points at a slot
+// since reused for another farm is rejected with `InvalidHandle`.
+typedef struct FarmHandle FarmHandle;
+
+// Cursor over the animals of a farm, created by `farm_iter_begin`.
+//
+// The cursor remembers which animals were in the farm when it was created.
+// Changing the farm while iterating is allowed: animals removed since are
+// skipped, animals added since are not visited, and renamed animals are
+// reported under their current name.
+typedef struct FarmIter FarmIter;
+
+// Identifies an animal for as long as it stays in its farm. Once the animal
+// is removed its id is rejected with `NotFound`, even after the farm reuses
+// the storage for another animal. 0 is never a valid id.
+typedef uint64_t AnimalId;
+
+// Reports what `add_animal` did with a new animal.
+typedef struct AddAnimalResult {
+  enum AddOutcome outcome;
+  // The pointer that was stored under the name before, when `outcome` is
+  // `Replaced` and the farm did not free it with a `drop` callback; null
+  // otherwise.
+  const void *previous_animal;
+  // The `n` the animal was stored as `<name>#<n>` under, when `outcome` is
+  // `Suffixed`; 0 otherwise.
+  uint32_t suffix;
+  // The id the new animal can be looked up by.
+  AnimalId id;
+} AddAnimalResult;
+
+typedef void (*SpeakFn)(const void*, const char*);
+
+typedef void (*DropFn)(const void*);
+
+typedef char *(*SpeakWithReplyFn)(const void*, const char*);
+
+typedef void (*FreeReplyFn)(char*);
+
+typedef char *(*SerializeFn)(const void*);
+
+typedef const void *(*DeserializeFn)(const char*, const char*);
+
+// Callbacks the farm uses to drive host animals.
+//
+// Hosts set `struct_size` to `sizeof(FunctionsMap)` as they compiled it and
+// `version` to the `FUNCTIONS_MAP_VERSION` they were built against. New slots
+// are only ever appended, so the library treats any slot past `struct_size`,
+// like any null slot, as not provided; a slot `struct_size` only partly
+// covers counts as past it. Which slots a map provides is decided by
+// `struct_size` alone: `version` is informational and never checked.
+typedef struct FunctionsMap {
+  size_t struct_size;
+  uint32_t version;
+  SpeakFn speak;
+  // Frees a host animal once the farm lets go of it: when it is removed,
+  // replaced by `add_animal`, or its farm is destroyed. Since version 2.
+  DropFn drop;
+  // Like `speak`, but returns what the animal said as a nul-terminated
+  // string, or null for no reply. Since version 3.
+  SpeakWithReplyFn speak_with_reply;
+  // Frees a reply returned by `speak_with_reply`, or state returned by
+  // `serialize`, once the farm has copied it. Left empty, the host keeps
+  // ownership of those strings. Since version 3.
+  FreeReplyFn free_reply;
+  // Returns the host state to save with an animal in a farm snapshot, as a
+  // nul-terminated UTF-8 string, or null for none. Since version 4.
+  SerializeFn serialize;
+  // Rebuilds a host animal when a snapshot is loaded, from its name and the
+  // state `serialize` returned (null if there was none), and returns the
+  // new animal pointer. Since version 4.
+  DeserializeFn deserialize;
+} FunctionsMap;
+
+typedef uint32_t SpeciesId;
+
+typedef bool (*SelectAnimalFn)(void*, AnimalId, const char*, const void*);
+
+typedef bool (*ForEachAnimalFn)(void*, AnimalId, const char*, const void*);
+
+// Describes a species to `register_species`, and is filled in by
+// `get_species_info`.
+//
+// Strings and the map returned by `get_species_info` are owned by the farm
+// and stay valid until it is destroyed.
+typedef struct SpeciesInfo {
+  const char *name;
+  // Null when registering means "same as `name`".
+  const char *display_name;
+  // What the species says when `native_speak` is given a null message. May
+  // be null.
+  const char *default_sound;
+  // The most animals of this species the farm will hold, or 0 for no limit.
+  size_t max_count;
+  // Callbacks for animals of this species. Null, like any slot left empty,
+  // falls back to the farm's map.
+  const struct FunctionsMap *functions_map;
+} SpeciesInfo;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Stores an animal under `animal_name`, resolving a name clash with the
+// farm's `DuplicatePolicy`. What happened is written to `out_result` unless
+// it is null.
+//
+// # Safety
+//
+// `farm_ptr` must come from `create_farm`, `animal_name` must be null or a
+// nul-terminated C string and `out_result` must be null or writable.
+enum FarmStatus add_animal(const struct FarmHandle *farm_ptr,
+                           const char *animal_name,
+                           const void *animal_ptr,
+                           struct AddAnimalResult *out_result);
+
+// Like `add_animal`, but the animal dispatches through its own
+// `functions_map` instead of the farm's. Slots the map leaves empty fall back
+// to the farm's; a null `functions_map` behaves exactly like `add_animal`.
+//
+// # Safety
+//
+// As for `add_animal`; `functions_map` must also be null or point to at least
+// `functions_map->struct_size` readable bytes. The farm keeps its own copy.
+enum FarmStatus add_animal_with_functions(const struct FarmHandle *farm_ptr,
+                                          const char *animal_name,
+                                          const void *animal_ptr,
+                                          const struct FunctionsMap *functions_map,
+                                          struct AddAnimalResult *out_result);
+
+// Like `add_animal`, but the animal belongs to a species registered with
+// `register_species` and dispatches through that species' map. Fails with
+// `LimitReached` if the species already has `max_count` animals.
+//
+// # Safety
+//
+// As for `add_animal`.
+enum FarmStatus add_animal_of_species(const struct FarmHandle *farm_ptr,
+                                      const char *animal_name,
+                                      const void *animal_ptr,
+                                      SpeciesId species_id,
+                                      struct AddAnimalResult *out_result);
+
+// Looks up an animal by name and writes its pointer to `out_animal`.
+//
+// # Safety
+//
+// `farm_ptr` must come from `create_farm`, `animal_name` must be null or a
+// nul-terminated C string and `out_animal` must be null or writable.
+enum FarmStatus get_animal(const struct FarmHandle *farm_ptr,
+                           const char *animal_name,
+                           const void **out_animal);
+
+// Like `get_animal`, but looks the animal up by the id `add_animal` gave it.
+//
+// # Safety
+//
+// `farm_ptr` must come from `create_farm` and `out_animal` must be null or
+// writable.
+enum FarmStatus get_animal_by_id(const struct FarmHandle *farm_ptr,
+                                 AnimalId animal_id,
+                                 const void **out_animal);
+
+// Writes the id of the animal stored under `animal_name` to `out_id`.
+//
+// # Safety
+//
+// `farm_ptr` must come from `create_farm`, `animal_name` must be null or a
+// nul-terminated C string and `out_id` must be null or writable.
+enum FarmStatus get_animal_id(const struct FarmHandle *farm_ptr,
+                              const char *animal_name,
+                              AnimalId *out_id);
+
+// Removes an animal from the farm. If the animal has a `drop` callback the
+// farm frees it with that and writes null to `out_animal`; otherwise it writes
+// the pointer the animal was stored with, handing ownership back to the host.
+//
+// # Safety
+//
+// `farm_ptr` must come from `create_farm`, `animal_name` must be null or a
+// nul-terminated C string and `out_animal` must be null or writable.
+enum FarmStatus remove_animal(const struct FarmHandle *farm_ptr,
+                              const char *animal_name,
+                              const void **out_animal);
+
+// Like `remove_animal`, but looks the animal up by the id `add_animal` gave
+// it.
+//
+// # Safety
+//
+// `farm_ptr` must come from `create_farm` and `out_animal` must be null or
+// writable.
+enum FarmStatus remove_animal_by_id(const struct FarmHandle *farm_ptr,
+                                    AnimalId animal_id,
+                                    const void **out_animal);
+
+// Moves an animal to a new name. Fails with `AlreadyExists`, leaving the farm
+// unchanged, if another animal already uses `new_name`.
+//
+// # Safety
+//
+// `farm_ptr` must come from `create_farm`; `old_name` and `new_name` must be
+// null or nul-terminated C strings.
+enum FarmStatus rename_animal(const struct FarmHandle *farm_ptr,
+                              const char *old_name,
+                              const char *new_name);
+
+// Creates a farm and writes its handle to `out_farm`. `duplicate_policy`, a
+// `DuplicatePolicy` value, decides how `add_animal` treats names that are
+// already taken; any other value fails with `InvalidArgument`.
+//
+// # Safety
+//
+// `functions_map` must be null or point to at least
+// `functions_map->struct_size` readable bytes; the farm keeps its own copy.
+// `out_farm` must be null or writable.
+enum FarmStatus create_farm(const struct FunctionsMap *functions_map,
+                            int duplicate_policy,
+                            const struct FarmHandle **out_farm);
+
+// Destroys a farm created by `create_farm`, invalidating its handle.
+//
+// Every animal still stored in the farm is freed with its `drop` callback.
+// For animals without one, `release_animal` is called instead if given, so
+// the host can free them. The handle is already invalid while these run, so
+// calls on it from inside them fail with `InvalidHandle`. Returns
+// `InvalidHandle` without touching anything if `farm_ptr` is not a live
+// farm, e.g. because it was already destroyed.
+//
+// Calls racing with this one on other threads either finish first or fail
+// with `InvalidHandle`; an `add_animal` that loses the race does not store
+// its animal.
+enum FarmStatus destroy_farm(const struct FarmHandle *farm_ptr,
+                             void (*release_animal)(const void*));
+
+// Calls the animal's `speak` with `message`, or with its species'
+// `default_sound` if `message` is null.
+//
+// # Safety
+//
+// `farm_ptr` must come from `create_farm`; `animal_name` and `message` must
+// be null or nul-terminated C strings.
+enum FarmStatus native_speak(const struct FarmHandle *farm_ptr,
+                             const char *animal_name,
+                             const char *message);
+
+// Like `native_speak`, but looks the animal up by the id `add_animal` gave
+// it.
+//
+// # Safety
+//
+// `farm_ptr` must come from `create_farm` and `message` must be null or a
+// nul-terminated C string.
+enum FarmStatus native_speak_by_id(const struct FarmHandle *farm_ptr,
+                                   AnimalId animal_id,
+                                   const char *message);
+
+// Makes every animal in the farm speak `message`, and writes how many did to
+// `out_spoken` if it is not null.
+//
+// Animals are visited as by `farm_iter_begin`, so callbacks may change the
+// farm. Animals that cannot speak, because they have no `speak` callback or
+// `message` is null and their species has no default sound, are skipped.
+//
+// # Safety
+//
+// `farm_ptr` must come from `create_farm`, `message` must be null or a
+// nul-terminated C string, and `out_spoken` must be null or writable.
+enum FarmStatus native_speak_all(const struct FarmHandle *farm_ptr,
+                                 const char *message,
+                                 size_t *out_spoken);
+
+// Like `native_speak_all`, but only animals for which `select` returns true
+// speak. `select` gets `user_data` and the animal's id, name and pointer,
+// and runs just before that animal would speak.
+//
+// # Safety
+//
+// As for `native_speak_all`.
+enum FarmStatus native_speak_where(const struct FarmHandle *farm_ptr,
+                                   const char *message,
+                                   SelectAnimalFn select,
+                                   void *user_data,
+                                   size_t *out_spoken);
+
+// Like `native_speak_all`, but only animals whose name matches
+// `name_pattern` speak. In the pattern `*` matches any run of characters,
+// `?` matches exactly one, and every other character matches itself.
+//
+// # Safety
+//
+// As for `native_speak_all`; `name_pattern` must also be null or a
+// nul-terminated C string.
+enum FarmStatus native_speak_matching(const struct FarmHandle *farm_ptr,
+                                      const char *message,
+                                      const char *name_pattern,
+                                      size_t *out_spoken);
+
+// Like `native_speak`, but goes through the animal's `speak_with_reply` and
+// writes a copy of what it said to `out_reply`, or null if it said nothing.
+// Free the copy with `farm_free_string`.
+//
+// # Safety
+//
+// As for `native_speak`; `out_reply` must also be null or writable.
+enum FarmStatus native_speak_with_reply(const struct FarmHandle *farm_ptr,
+                                        const char *animal_name,
+                                        const char *message,
+                                        char **out_reply);
+
+// Frees a string handed out by the farm, such as a reply from
+// `native_speak_with_reply`. Null is ignored.
+//
+// # Safety
+//
+// `string` must be null or a string the farm handed out that has not been
+// freed yet.
+void farm_free_string(char *string);
+
+// Tags an animal with `tag`. Tagging it twice with the same tag is not an
+// error.
+//
+// # Safety
+//
+// `farm_ptr` must come from `create_farm`, and `animal_name` and `tag` must
+// be null or nul-terminated C strings.
+enum FarmStatus add_animal_tag(const struct FarmHandle *farm_ptr,
+                               const char *animal_name,
+                               const char *tag);
+
+// Removes `tag` from an animal. Fails with `NotFound` if it is not tagged
+// with it.
+//
+// # Safety
+//
+// As for `add_animal_tag`.
+enum FarmStatus remove_animal_tag(const struct FarmHandle *farm_ptr,
+                                  const char *animal_name,
+                                  const char *tag);
+
+// Writes whether an animal is tagged with `tag` to `out_has_tag`.
+//
+// # Safety
+//
+// As for `add_animal_tag`; `out_has_tag` must also be null or writable.
+enum FarmStatus animal_has_tag(const struct FarmHandle *farm_ptr,
+                               const char *animal_name,
+                               const char *tag,
+                               bool *out_has_tag);
+
+// Writes the ids of every animal tagged with `tag` to `out_ids` and their
+// number to `out_count`, in the same way as `list_species`.
+//
+// # Safety
+//
+// `farm_ptr` must come from `create_farm`, `tag` must be null or a
+// nul-terminated C string, `out_ids` must be null or valid for writes of
+// `capacity` ids and `out_count` must be null or writable.
+enum FarmStatus farm_animals_with_tag(const struct FarmHandle *farm_ptr,
+                                      const char *tag,
+                                      AnimalId *out_ids,
+                                      size_t capacity,
+                                      size_t *out_count);
+
+// Sets an animal's attribute `key` to an integer, replacing any value it
+// had, whatever its kind.
+//
+// # Safety
+//
+// `farm_ptr` must come from `create_farm`, and `animal_name` and `key` must
+// be null or nul-terminated C strings.
+enum FarmStatus set_animal_attribute_int(const struct FarmHandle *farm_ptr,
+                                         const char *animal_name,
+                                         const char *key,
+                                         int64_t value);
+
+// Like `set_animal_attribute_int`, for a floating-point value.
+//
+// # Safety
+//
+// As for `set_animal_attribute_int`.
+enum FarmStatus set_animal_attribute_float(const struct FarmHandle *farm_ptr,
+                                           const char *animal_name,
+                                           const char *key,
+                                           double value);
+
+// Like `set_animal_attribute_int`, for a string value. The farm keeps its
+// own copy of `value`.
+//
+// # Safety
+//
+// As for `set_animal_attribute_int`; `value` must also be null or a
+// nul-terminated C string.
+enum FarmStatus set_animal_attribute_string(const struct FarmHandle *farm_ptr,
+                                            const char *animal_name,
+                                            const char *key,
+                                            const char *value);
+
+// Like `set_animal_attribute_int`, for a boolean value.
+//
+// # Safety
+//
+// As for `set_animal_attribute_int`.
+enum FarmStatus set_animal_attribute_bool(const struct FarmHandle *farm_ptr,
+                                          const char *animal_name,
+                                          const char *key,
+                                          bool value);
+
+// Removes an animal's attribute `key`. Fails with `NotFound` if it has none.
+//
+// # Safety
+//
+// As for `set_animal_attribute_int`.
+enum FarmStatus remove_animal_attribute(const struct FarmHandle *farm_ptr,
+                                        const char *animal_name,
+                                        const char *key);
+
+// Writes the kind of an animal's attribute `key` to `out_kind`.
+//
+// # Safety
+//
+// As for `set_animal_attribute_int`; `out_kind` must also be null or
+// writable.
+enum FarmStatus get_animal_attribute_kind(const struct FarmHandle *farm_ptr,
+                                          const char *animal_name,
+                                          const char *key,
+                                          enum AttributeKind *out_kind);
+
+// Writes an animal's integer attribute `key` to `out_value`. Fails with
+// `InvalidArgument` if the attribute holds another kind of value.
+//
+// # Safety
+//
+// As for `set_animal_attribute_int`; `out_value` must also be null or
+// writable.
+enum FarmStatus get_animal_attribute_int(const struct FarmHandle *farm_ptr,
+                                         const char *animal_name,
+                                         const char *key,
+                                         int64_t *out_value);
+
+// Like `get_animal_attribute_int`, for a floating-point attribute.
+//
+// # Safety
+//
+// As for `get_animal_attribute_int`.
+enum FarmStatus get_animal_attribute_float(const struct FarmHandle *farm_ptr,
+                                           const char *animal_name,
+                                           const char *key,
+                                           double *out_value);
+
+// Like `get_animal_attribute_int`, for a string attribute. Writes a copy of
+// the string; free it with `farm_free_string`.
+//
+// # Safety
+//
+// As for `get_animal_attribute_int`.
+enum FarmStatus get_animal_attribute_string(const struct FarmHandle *farm_ptr,
+                                            const char *animal_name,
+                                            const char *key,
+                                            char **out_value);
+
+// Like `get_animal_attribute_int`, for a boolean attribute.
+//
+// # Safety
+//
+// As for `get_animal_attribute_int`.
+enum FarmStatus get_animal_attribute_bool(const struct FarmHandle *farm_ptr,
+                                          const char *animal_name,
+                                          const char *key,
+                                          bool *out_value);
+
+// Like `farm_save_json`, but saves the farm in a compact binary format that
+// is much faster to write and read for large farms. Writes the data to
+// `out_data` and its size to `out_len`; free it with `farm_free_buffer`.
+//
+// # Safety
+//
+// `farm_ptr` must come from `create_farm`, and `out_data` and `out_len` must
+// be null or writable.
+enum FarmStatus farm_save_binary(const struct FarmHandle *farm_ptr,
+                                 uint8_t **out_data,
+                                 size_t *out_len);
+
+// Like `farm_load_json`, for data saved by `farm_save_binary`, including by
+// older versions of this library. Fails with `NotSupported` if the data
+// comes from a newer version that changed the format incompatibly, and
+// with `InvalidArgument` if the strings it holds would take more than 1 GiB
+// once loaded.
+//
+// # Safety
+//
+// `farm_ptr` must come from `create_farm`, `data` must be null or valid for
+// reads of `len` bytes and `out_count` must be null or writable.
+enum FarmStatus farm_load_binary(const struct FarmHandle *farm_ptr,
+                                 const uint8_t *data,
+                                 size_t len,
+                                 size_t *out_count);
+
+// Frees data handed out by `farm_save_binary`. Null is ignored.
+//
+// # Safety
+//
+// `data` must be null or data from `farm_save_binary` that has not been
+// freed yet, and `len` the size written with it.
+void farm_free_buffer(uint8_t *data, size_t len);
+
+// Writes how many animals the farm holds to `out_count`.
+//
+// # Safety
+//
+// `out_count` must be null or writable.
+enum FarmStatus farm_animal_count(const struct FarmHandle *farm_ptr, size_t *out_count);
+
+// Starts iterating over the farm's animals and writes the cursor to
+// `out_iter`. Free it with `farm_iter_end`; it keeps working, yielding no
+// more animals, if the farm is destroyed first.
+//
+// # Safety
+//
+// `out_iter` must be null or writable.
+enum FarmStatus farm_iter_begin(const struct FarmHandle *farm_ptr, struct FarmIter **out_iter);
+
+// Advances the cursor and writes the next animal's id, name and pointer to
+// the out-parameters that are not null. Returns `Exhausted` once every
+// animal has been visited.
+//
+// The name stays valid until the next call on this cursor.
+//
+// # Safety
+//
+// `iter` must come from `farm_iter_begin`, not be freed yet, and not be used
+// from two threads at once. Each out-parameter must be null or writable.
+enum FarmStatus farm_iter_next(struct FarmIter *iter,
+                               AnimalId *out_id,
+                               const char **out_name,
+                               const void **out_animal);
+
+// Frees a cursor created by `farm_iter_begin`. Null is ignored.
+//
+// # Safety
+//
+// `iter` must be null or come from `farm_iter_begin` and not be freed yet.
+void farm_iter_end(struct FarmIter *iter);
+
+// Calls `callback` with `user_data` and each animal's id, name and pointer,
+// until it returns false or every animal has been visited.
+//
+// The farm is not locked while `callback` runs, and changes it makes to the
+// farm follow the same rules as for `FarmIter`. The name is only valid for
+// the duration of the call.
+enum FarmStatus farm_for_each_animal(const struct FarmHandle *farm_ptr,
+                                     ForEachAnimalFn callback,
+                                     void *user_data);
+
+// Writes the ids of every animal matching `query` to `out_ids` and their
+// number to `out_count`, in the same way as `list_species`. Fails with
+// `InvalidArgument` if `query` is malformed; the last error message says
+// where.
+//
+// A query combines conditions with `&&`, `||`, `!` and parentheses. A
+// condition is either `tag:<tag>` or a comparison of `name`, `species` or an
+// attribute key with a string, number or `true`/`false` using `==`, `!=`,
+// `<`, `<=`, `>`, `>=`, or `~` for a name pattern. Comparisons with a missing
+// attribute, or of values of different kinds, are false.
+//
+// # Safety
+//
+// `farm_ptr` must come from `create_farm`, `query` must be null or a
+// nul-terminated C string, `out_ids` must be null or valid for writes of
+// `capacity` ids and `out_count` must be null or writable.
+enum FarmStatus farm_query(const struct FarmHandle *farm_ptr,
+                           const char *query,
+                           AnimalId *out_ids,
+                           size_t capacity,
+                           size_t *out_count);
+
+// Saves the farm's species and animals as JSON and writes the document to
+// `out_json`; free it with `farm_free_string`.
+//
+// Animals are saved with their name, species, tags and attributes, plus
+// whatever the host's `serialize` callback returns for them. Their ids and
+// `FunctionsMap`s are not saved. NaN and infinite float attributes are saved
+// as the strings "NaN", "inf" and "-inf".
+//
+// # Safety
+//
+// `farm_ptr` must come from `create_farm` and `out_json` must be null or
+// writable.
+enum FarmStatus farm_save_json(const struct FarmHandle *farm_ptr, char **out_json);
+
+// Loads a document made by `farm_save_json` into the farm, rebuilding each
+// animal with the host's `deserialize` callback, and writes how many
+// animals were loaded to `out_count` if it is not null.
+//
+// Species the farm lacks are registered without a `FunctionsMap`; ones it
+// already has, looked up by name, are used as they are. Animals are added
+// as by `add_animal`, so name clashes follow the farm's `DuplicatePolicy`,
+// and get new ids. If an animal cannot be added, it is dropped, loading
+// stops with that error and the animals loaded before it stay in the farm.
+// Fails with `InvalidArgument` if `json` is not a snapshot, and with
+// `NotSupported` if it comes from a newer, incompatible library.
+//
+// # Safety
+//
+// `farm_ptr` must come from `create_farm`, `json` must be null or a
+// nul-terminated C string and `out_count` must be null or writable.
+enum FarmStatus farm_load_json(const struct FarmHandle *farm_ptr,
+                               const char *json,
+                               size_t *out_count);
+
+// Registers a species and writes its id to `out_species_id`. Fails with
+// `AlreadyExists` if the farm already has a species with the same name.
+//
+// # Safety
+//
+// `farm_ptr` must come from `create_farm` and `info` must be null or valid,
+// with each string null or nul-terminated and `functions_map` null or
+// pointing to at least `functions_map->struct_size` readable bytes. The farm
+// copies everything it needs. `out_species_id` must be null or writable.
+enum FarmStatus register_species(const struct FarmHandle *farm_ptr,
+                                 const struct SpeciesInfo *info,
+                                 SpeciesId *out_species_id);
+
+// Writes the id of the species registered under `name` to `out_species_id`.
+//
+// # Safety
+//
+// `farm_ptr` must come from `create_farm`, `name` must be null or a
+// nul-terminated C string and `out_species_id` must be null or writable.
+enum FarmStatus find_species(const struct FarmHandle *farm_ptr,
+                             const char *name,
+                             SpeciesId *out_species_id);
+
+// Writes the ids of every registered species to `out_ids` and their number
+// to `out_count`.
+//
+// Pass a null `out_ids` to only query the number. Fails with
+// `BufferTooSmall`, after still writing `out_count`, if `capacity` cannot hold
+// every id.
+//
+// # Safety
+//
+// `farm_ptr` must come from `create_farm`, `out_ids` must be null or valid for
+// writes of `capacity` ids and `out_count` must be null or writable.
+enum FarmStatus list_species(const struct FarmHandle *farm_ptr,
+                             SpeciesId *out_ids,
+                             size_t capacity,
+                             size_t *out_count);
+
+// Writes the description of a registered species to `out_info`.
+//
+// # Safety
+//
+// `farm_ptr` must come from `create_farm` and `out_info` must be null or
+// writable.
+enum FarmStatus get_species_info(const struct FarmHandle *farm_ptr,
+                                 SpeciesId species_id,
+                                 struct SpeciesInfo *out_info);
+
+// Writes how many animals of a species the farm currently holds to
+// `out_count`.
+//
+// # Safety
+//
+// `farm_ptr` must come from `create_farm` and `out_count` must be null or
+// writable.
+enum FarmStatus species_animal_count(const struct FarmHandle *farm_ptr,
+                                     SpeciesId species_id,
+                                     size_t *out_count);
+
+// Returns the size in bytes, including the nul terminator, of the message
+// describing the last error on the calling thread, or 0 if there is none.
+size_t farm_last_error_length(void);
+
+// Copies the message describing the last error on the calling thread into
+// `buffer` as a nul-terminated string.
+//
+// Returns `NotFound` if no error has been recorded and `BufferTooSmall` if
+// `length` is less than `farm_last_error_length()`. Neither outcome replaces
+// the stored message.
+//
+// # Safety
+//
+// `buffer` must be null or valid for writes of `length` bytes.
+enum FarmStatus farm_last_error_message(char *buffer, size_t length);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* ANIMAL_FARM_H */
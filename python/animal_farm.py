@@ -0,0 +1,356 @@
+"""Python bindings for the animal_farm library, built on ctypes.
+
+Any Python object with a ``speak(message)`` method can live in a farm::
+
+    from animal_farm import Farm
+
+    class Cow:
+        def speak(self, message):
+            print("Cow says", message)
+
+    with Farm() as farm:
+        farm.add_animal("daisy", Cow())
+        farm.speak("daisy", "moo")
+
+The farm keeps a reference to each animal for as long as it stores it, and
+lets go of it when the animal is removed, replaced or the farm is closed.
+
+The shared library is looked up in ``ANIMAL_FARM_LIB`` if set, then next to
+this module, then in the checkout's ``target/release`` and ``target/debug``,
+then wherever the system keeps libraries.
+"""
+
+import ctypes
+import ctypes.util
+import enum
+import itertools
+import os
+import sys
+import threading
+from typing import NamedTuple
+
+__all__ = [
+    "AddOutcome",
+    "AddResult",
+    "DuplicatePolicy",
+    "Farm",
+    "FarmError",
+    "FarmStatus",
+]
+
+# The FunctionsMap layout these bindings are written against.
+FUNCTIONS_MAP_VERSION = 4
+
+
+class FarmStatus(enum.IntEnum):
+    OK = 0
+    NULL_POINTER = 1
+    INVALID_UTF8 = 2
+    NOT_FOUND = 3
+    ALREADY_EXISTS = 4
+    INVALID_HANDLE = 5
+    PANIC = 6
+    BUFFER_TOO_SMALL = 7
+    INVALID_ARGUMENT = 8
+    NOT_SUPPORTED = 9
+    LIMIT_REACHED = 10
+    EXHAUSTED = 11
+
+
+class DuplicatePolicy(enum.IntEnum):
+    REJECT = 0
+    REPLACE = 1
+    KEEP_BOTH = 2
+
+
+class AddOutcome(enum.IntEnum):
+    ADDED = 0
+    REPLACED = 1
+    SUFFIXED = 2
+
+
+class AddResult(NamedTuple):
+    """What ``Farm.add_animal`` did with a new animal."""
+
+    outcome: AddOutcome
+    # The n the animal was stored as "<name>#<n>" under, or 0.
+    suffix: int
+    id: int
+
+
+class FarmError(Exception):
+    """A call into the library failed with ``status``."""
+
+    def __init__(self, status, message):
+        super().__init__(message)
+        self.status = status
+
+
+_SpeakFn = ctypes.CFUNCTYPE(None, ctypes.c_void_p, ctypes.c_char_p)
+_DropFn = ctypes.CFUNCTYPE(None, ctypes.c_void_p)
+# The slots below are never filled in by these bindings; they are declared so
+# the structure has the size the library expects.
+_SpeakWithReplyFn = ctypes.CFUNCTYPE(ctypes.c_void_p, ctypes.c_void_p, ctypes.c_char_p)
+_FreeReplyFn = ctypes.CFUNCTYPE(None, ctypes.c_void_p)
+_SerializeFn = ctypes.CFUNCTYPE(ctypes.c_void_p, ctypes.c_void_p)
+_DeserializeFn = ctypes.CFUNCTYPE(ctypes.c_void_p, ctypes.c_char_p, ctypes.c_char_p)
+
+
+class _FunctionsMap(ctypes.Structure):
+    _fields_ = [
+        ("struct_size", ctypes.c_size_t),
+        ("version", ctypes.c_uint32),
+        ("speak", _SpeakFn),
+        ("drop", _DropFn),
+        ("speak_with_reply", _SpeakWithReplyFn),
+        ("free_reply", _FreeReplyFn),
+        ("serialize", _SerializeFn),
+        ("deserialize", _DeserializeFn),
+    ]
+
+
+class _AddAnimalResult(ctypes.Structure):
+    _fields_ = [
+        ("outcome", ctypes.c_int),
+        ("previous_animal", ctypes.c_void_p),
+        ("suffix", ctypes.c_uint32),
+        ("id", ctypes.c_uint64),
+    ]
+
+
+def _library_names():
+    if sys.platform == "win32":
+        return ["animal_farm.dll"]
+    if sys.platform == "darwin":
+        return ["libanimal_farm.dylib"]
+    return ["libanimal_farm.so"]
+
+
+def _find_library():
+    path = os.environ.get("ANIMAL_FARM_LIB")
+    if path:
+        return path
+
+    here = os.path.dirname(os.path.abspath(__file__))
+    checkout = os.path.dirname(here)
+    for directory in [
+        here,
+        os.path.join(checkout, "target", "release"),
+        os.path.join(checkout, "target", "debug"),
+    ]:
+        for name in _library_names():
+            candidate = os.path.join(directory, name)
+            if os.path.exists(candidate):
+                return candidate
+
+    path = ctypes.util.find_library("animal_farm")
+    if path is None:
+        raise OSError(
+            "Could not find the animal_farm library; build it with "
+            "`cargo build --release` or set ANIMAL_FARM_LIB"
+        )
+    return path
+
+
+def _load_library():
+    lib = ctypes.CDLL(_find_library())
+    handle = ctypes.c_void_p
+    status = ctypes.c_int
+
+    lib.create_farm.argtypes = [
+        ctypes.POINTER(_FunctionsMap),
+        ctypes.c_int,
+        ctypes.POINTER(handle),
+    ]
+    lib.create_farm.restype = status
+    lib.destroy_farm.argtypes = [handle, _DropFn]
+    lib.destroy_farm.restype = status
+    lib.add_animal.argtypes = [
+        handle,
+        ctypes.c_char_p,
+        ctypes.c_void_p,
+        ctypes.POINTER(_AddAnimalResult),
+    ]
+    lib.add_animal.restype = status
+    lib.get_animal.argtypes = [handle, ctypes.c_char_p, ctypes.POINTER(ctypes.c_void_p)]
+    lib.get_animal.restype = status
+    lib.remove_animal.argtypes = [handle, ctypes.c_char_p, ctypes.POINTER(ctypes.c_void_p)]
+    lib.remove_animal.restype = status
+    lib.native_speak.argtypes = [handle, ctypes.c_char_p, ctypes.c_char_p]
+    lib.native_speak.restype = status
+    lib.farm_animal_count.argtypes = [handle, ctypes.POINTER(ctypes.c_size_t)]
+    lib.farm_animal_count.restype = status
+    lib.farm_last_error_length.argtypes = []
+    lib.farm_last_error_length.restype = ctypes.c_size_t
+    lib.farm_last_error_message.argtypes = [ctypes.c_char_p, ctypes.c_size_t]
+    lib.farm_last_error_message.restype = status
+    return lib
+
+
+_lib = None
+_lib_lock = threading.Lock()
+
+
+def _library():
+    global _lib
+    with _lib_lock:
+        if _lib is None:
+            _lib = _load_library()
+        return _lib
+
+
+def _last_error():
+    lib = _library()
+    length = lib.farm_last_error_length()
+    if length == 0:
+        return ""
+    buffer = ctypes.create_string_buffer(length)
+    if lib.farm_last_error_message(buffer, length) != FarmStatus.OK:
+        return ""
+    return buffer.value.decode("utf-8", "replace")
+
+
+def _check(status):
+    if status != FarmStatus.OK:
+        raise FarmError(FarmStatus(status), _last_error())
+
+
+def _encode(text, what):
+    if not isinstance(text, str):
+        raise TypeError(f"{what} must be a str, not {type(text).__name__}")
+    return text.encode("utf-8")
+
+
+class Farm:
+    """A farm whose animals are Python objects with a ``speak`` method.
+
+    Close the farm with ``close`` or by using it as a context manager; a farm
+    that is garbage collected is closed then. Closing lets go of every animal
+    still in it.
+    """
+
+    def __init__(self, duplicate_policy=DuplicatePolicy.REJECT):
+        self._lib = _library()
+        # What the library knows each animal by: a token standing in for the
+        # animal pointer, mapped to the object here. An entry lives exactly as
+        # long as the farm stores the animal.
+        self._animals = {}
+        self._tokens = itertools.count(1)
+        # An exception raised by an animal's `speak`, to be re-raised once
+        # the call that made it speak returns.
+        self._pending = threading.local()
+
+        # The farm copies the map, but the callbacks must outlive it.
+        self._speak = _SpeakFn(self._speak_animal)
+        self._drop = _DropFn(self._drop_animal)
+        functions_map = _FunctionsMap(
+            struct_size=ctypes.sizeof(_FunctionsMap),
+            version=FUNCTIONS_MAP_VERSION,
+            speak=self._speak,
+            drop=self._drop,
+        )
+
+        handle = ctypes.c_void_p()
+        _check(
+            self._lib.create_farm(
+                ctypes.byref(functions_map), int(duplicate_policy), ctypes.byref(handle)
+            )
+        )
+        self._handle = handle
+
+    def _speak_animal(self, token, message):
+        try:
+            self._animals[token].speak(message.decode("utf-8", "replace"))
+        except BaseException as error:
+            self._pending.error = error
+
+    def _drop_animal(self, token):
+        self._animals.pop(token, None)
+
+    def _raise_pending(self):
+        error = getattr(self._pending, "error", None)
+        if error is not None:
+            self._pending.error = None
+            raise error
+
+    def _live_handle(self):
+        if self._handle is None:
+            raise FarmError(FarmStatus.INVALID_HANDLE, "The farm is closed")
+        return self._handle
+
+    def add_animal(self, name, animal):
+        """Stores ``animal`` under ``name`` and returns an ``AddResult``.
+
+        Name clashes are resolved with the farm's ``DuplicatePolicy``; an
+        animal pushed out by ``REPLACE`` is let go of.
+        """
+        handle = self._live_handle()
+        encoded_name = _encode(name, "name")
+        if not callable(getattr(animal, "speak", None)):
+            raise TypeError("animal must have a speak(message) method")
+
+        token = next(self._tokens)
+        self._animals[token] = animal
+        result = _AddAnimalResult()
+        status = self._lib.add_animal(handle, encoded_name, token, ctypes.byref(result))
+        if status != FarmStatus.OK:
+            del self._animals[token]
+            _check(status)
+        return AddResult(AddOutcome(result.outcome), result.suffix, result.id)
+
+    def get_animal(self, name):
+        """Returns the animal stored under ``name``."""
+        token = ctypes.c_void_p()
+        _check(
+            self._lib.get_animal(
+                self._live_handle(), _encode(name, "name"), ctypes.byref(token)
+            )
+        )
+        return self._animals[token.value]
+
+    def remove_animal(self, name):
+        """Removes the animal stored under ``name`` and returns it."""
+        animal = self.get_animal(name)
+        token = ctypes.c_void_p()
+        _check(
+            self._lib.remove_animal(
+                self._live_handle(), _encode(name, "name"), ctypes.byref(token)
+            )
+        )
+        return animal
+
+    def speak(self, name, message=None):
+        """Calls the ``speak`` method of the animal stored under ``name``.
+
+        An exception raised by ``speak`` propagates from here.
+        """
+        encoded = None if message is None else _encode(message, "message")
+        status = self._lib.native_speak(
+            self._live_handle(), _encode(name, "name"), encoded
+        )
+        self._raise_pending()
+        _check(status)
+
+    def __len__(self):
+        count = ctypes.c_size_t()
+        _check(self._lib.farm_animal_count(self._live_handle(), ctypes.byref(count)))
+        return count.value
+
+    def close(self):
+        """Destroys the farm and lets go of its animals. Closing twice is
+        harmless."""
+        if self._handle is None:
+            return
+        handle, self._handle = self._handle, None
+        _check(self._lib.destroy_farm(handle, _DropFn()))
+        self._animals.clear()
+
+    def __enter__(self):
+        return self
+
+    def __exit__(self, *exc_info):
+        self.close()
+
+    def __del__(self):
+        if getattr(self, "_handle", None) is not None:
+            self.close()
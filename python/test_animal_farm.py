@@ -0,0 +1,82 @@
+import gc
+import unittest
+import weakref
+
+from animal_farm import AddOutcome, DuplicatePolicy, Farm, FarmError, FarmStatus
+
+
+class Cow:
+    def __init__(self, name):
+        self.name = name
+        self.heard = []
+
+    def speak(self, message):
+        self.heard.append(message)
+
+
+class Sheep:
+    def speak(self, message):
+        raise ValueError(f"sheep cannot say {message}")
+
+
+class FarmTest(unittest.TestCase):
+    def test_add_get_speak_remove(self):
+        with Farm() as farm:
+            daisy = Cow("daisy")
+            result = farm.add_animal("daisy", daisy)
+            self.assertEqual(result.outcome, AddOutcome.ADDED)
+            self.assertEqual(len(farm), 1)
+
+            self.assertIs(farm.get_animal("daisy"), daisy)
+            farm.speak("daisy", "moo")
+            self.assertEqual(daisy.heard, ["moo"])
+
+            self.assertIs(farm.remove_animal("daisy"), daisy)
+            self.assertEqual(len(farm), 0)
+            with self.assertRaises(FarmError) as error:
+                farm.get_animal("daisy")
+            self.assertEqual(error.exception.status, FarmStatus.NOT_FOUND)
+
+    def test_farm_keeps_animals_alive(self):
+        farm = Farm(DuplicatePolicy.REPLACE)
+        farm.add_animal("daisy", Cow("old"))
+        gc.collect()
+        self.assertEqual(farm.get_animal("daisy").name, "old")
+
+        old = weakref.ref(farm.get_animal("daisy"))
+        farm.add_animal("daisy", Cow("new"))
+        gc.collect()
+        self.assertIsNone(old())
+
+        new = weakref.ref(farm.get_animal("daisy"))
+        farm.close()
+        gc.collect()
+        self.assertIsNone(new())
+        with self.assertRaises(FarmError) as error:
+            farm.speak("daisy", "moo")
+        self.assertEqual(error.exception.status, FarmStatus.INVALID_HANDLE)
+
+    def test_rejected_duplicates_are_not_kept(self):
+        with Farm() as farm:
+            farm.add_animal("daisy", Cow("daisy"))
+            with self.assertRaises(FarmError) as error:
+                farm.add_animal("daisy", Cow("impostor"))
+            self.assertEqual(error.exception.status, FarmStatus.ALREADY_EXISTS)
+            self.assertEqual(len(farm._animals), 1)
+
+    def test_speak_exceptions_propagate(self):
+        with Farm() as farm:
+            farm.add_animal("shaun", Sheep())
+            with self.assertRaisesRegex(ValueError, "sheep cannot say baa"):
+                farm.speak("shaun", "baa")
+
+    def test_speak_without_message_needs_a_default_sound(self):
+        with Farm() as farm:
+            farm.add_animal("daisy", Cow("daisy"))
+            with self.assertRaises(FarmError) as error:
+                farm.speak("daisy")
+            self.assertEqual(error.exception.status, FarmStatus.NULL_POINTER)
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -0,0 +1,29 @@
+#!/bin/sh
+# Builds a release of animal_farm and installs the libraries, header,
+# pkg-config file and CMake package under PREFIX:
+#
+#     scripts/install.sh /usr/local
+set -eu
+
+prefix=${1:?usage: install.sh PREFIX}
+mkdir -p "$prefix"
+prefix=$(cd "$prefix" && pwd)
+cd "$(dirname "$0")/.."
+
+cargo build --release
+out=target/release
+
+install -d "$prefix/include" "$prefix/lib/pkgconfig" "$prefix/lib/cmake/animal_farm"
+install -m 644 include/animal_farm.h "$prefix/include/"
+for lib in libanimal_farm.a libanimal_farm.so libanimal_farm.dylib; do
+    if [ -f "$out/$lib" ]; then
+        install -m 644 "$out/$lib" "$prefix/lib/"
+    fi
+done
+
+package=$(mktemp -d)
+trap 'rm -rf "$package"' EXIT
+scripts/package-files.sh "$prefix/include" "$prefix/lib" "$package"
+install -m 644 "$package/animal_farm.pc" "$prefix/lib/pkgconfig/"
+install -m 644 "$package/cmake/animal_farmConfig.cmake" \
+    "$package/cmake/animal_farmConfigVersion.cmake" "$prefix/lib/cmake/animal_farm/"
@@ -0,0 +1,43 @@
+#!/bin/sh
+# Writes the pkg-config file and CMake package files for animal_farm into
+# OUTDIR, rendered from the templates in pkg/, pointing at INCLUDEDIR for the
+# header and at LIBDIR for the libraries:
+#
+#     scripts/package-files.sh INCLUDEDIR LIBDIR OUTDIR
+#
+# OUTDIR gets animal_farm.pc and, under cmake/, animal_farmConfig.cmake and
+# animal_farmConfigVersion.cmake. Relative directories are taken from the
+# current one.
+set -eu
+
+usage="usage: package-files.sh INCLUDEDIR LIBDIR OUTDIR"
+includedir=$(cd "${1:?$usage}" && pwd)
+libdir=$(cd "${2:?$usage}" && pwd)
+outdir=${3:?$usage}
+root=$(cd "$(dirname "$0")/.." && pwd)
+
+version=$(sed -n 's/^version = "\(.*\)"$/\1/p' "$root/Cargo.toml" | head -n 1)
+
+# What a C program must link besides the static library, as reported by
+# `rustc --print native-static-libs` for each platform.
+case $(uname -s) in
+    Linux) native_static_libs="-lgcc_s -lutil -lrt -lpthread -lm -ldl -lc" ;;
+    Darwin) native_static_libs="-liconv -lSystem -lc -lm" ;;
+    *) native_static_libs="-lpthread -lm -lc" ;;
+esac
+native_static_libs_cmake=$(echo "$native_static_libs" | sed 's/-l//g; s/ /;/g')
+
+render() {
+    sed -e "s|@VERSION@|$version|g" \
+        -e "s|@VERSION_MAJOR@|${version%%.*}|g" \
+        -e "s|@INCLUDEDIR@|$includedir|g" \
+        -e "s|@LIBDIR@|$libdir|g" \
+        -e "s|@NATIVE_STATIC_LIBS@|$native_static_libs|g" \
+        -e "s|@NATIVE_STATIC_LIBS_CMAKE@|$native_static_libs_cmake|g" \
+        "$root/pkg/$1.in" >"$2"
+}
+
+mkdir -p "$outdir/cmake"
+render animal_farm.pc "$outdir/animal_farm.pc"
+render animal_farmConfig.cmake "$outdir/cmake/animal_farmConfig.cmake"
+render animal_farmConfigVersion.cmake "$outdir/cmake/animal_farmConfigVersion.cmake"
@@ -0,0 +1,412 @@
+// The safe Rust interface to farms. The animal exports in lib.rs are thin
+// shims over `Farm`: they check their arguments, call the matching method and
+// turn the result into a `FarmStatus`. The exports in the other modules work
+// on `farm::Farm` directly and are not covered by `Farm`.
+//
+// Rust animals are stored like host animals, as an opaque pointer with a
+// `FunctionsMap` of their own: the pointer comes from `Arc::into_raw`, and the
+// map's `speak` and `drop` are trampolines instantiated for the animal's type.
+
+use std::{
+    any::{Any, TypeId},
+    cell::RefCell,
+    ffi::{c_char, c_void, CStr, CString},
+    panic::{catch_unwind, resume_unwind, AssertUnwindSafe},
+    sync::Arc,
+};
+
+use crate::{
+    farm::{
+        self, AddAnimalResult, AnimalEntry, AnimalId, DuplicatePolicy, Evicted, RustAnimal,
+        SharedFarm, SpeakTarget,
+    },
+    functions_map::{missing_slot, FunctionsMap},
+    glob,
+    iter::{FarmIter, Visit},
+    status::{FarmError, FarmStatus},
+};
+
+/// An animal kept by a `Farm` created from Rust.
+///
+/// The farm owns the animal once it is added and drops it when it is removed,
+/// replaced, or the farm itself is dropped. `speak` is called without any
+/// lock held, so it may use the farm, even to remove the animal itself, which
+/// is then dropped once `speak` returns. A panic in it reaches the caller of the
+/// `Farm` method that made the animal speak.
+pub trait Animal: Send + Sync + 'static {
+    fn speak(&self, message: &str);
+}
+
+/// A farm driven from Rust.
+///
+/// Every method takes `&self`, so a farm can be shared between threads, and
+/// the same rules as for the C API apply: animals are identified by name or
+/// by the `AnimalId` they were given when added, and a name clash is resolved
+/// with the farm's `DuplicatePolicy`.
+#[derive(Debug)]
+pub struct Farm {
+    shared: Arc<SharedFarm>,
+}
+
+thread_local! {
+    // A panic caught in `speak_animal`, to be resumed once the call that made
+    // the animal speak is back in Rust.
+    static ANIMAL_PANIC: RefCell<Option<Box<dyn Any + Send>>> = const { RefCell::new(None) };
+}
+
+extern "C" fn speak_animal<A: Animal>(animal_ptr: *const c_void, message: *const c_char) {
+    let animal = unsafe { &*(animal_ptr as *const A) };
+    let message = unsafe { CStr::from_ptr(message) }.to_string_lossy();
+
+    if let Err(payload) = catch_unwind(AssertUnwindSafe(|| animal.speak(&message))) {
+        ANIMAL_PANIC.with(|panic| *panic.borrow_mut() = Some(payload));
+    }
+}
+
+extern "C" fn drop_animal<A: Animal>(animal_ptr: *const c_void) {
+    drop(unsafe { Arc::from_raw(animal_ptr as *const A) });
+}
+
+unsafe fn retain_animal<A: Animal>(animal_ptr: *const c_void) {
+    unsafe { Arc::increment_strong_count(animal_ptr as *const A) };
+}
+
+unsafe fn release_animal<A: Animal>(animal_ptr: *const c_void) {
+    unsafe { Arc::decrement_strong_count(animal_ptr as *const A) };
+}
+
+fn resume_animal_panic() {
+    if let Some(payload) = ANIMAL_PANIC.with(|panic| panic.borrow_mut().take()) {
+        resume_unwind(payload);
+    }
+}
+
+fn message_arg(message: &str) -> Result<CString, FarmError> {
+    CString::new(message)
+        .map_err(|_| FarmError::new(FarmStatus::InvalidArgument, "`message` contains a nul byte"))
+}
+
+// Names are handed to C callers and hosts as C strings, so they cannot hold a
+// nul byte.
+fn name_arg(name: &str, arg_name: &str) -> Result<(), FarmError> {
+    if name.contains('\0') {
+        return Err(FarmError::new(
+            FarmStatus::InvalidArgument,
+            format!("`{}` contains a nul byte", arg_name),
+        ));
+    }
+    Ok(())
+}
+
+fn speak(target: SpeakTarget, message: *const c_char) -> Result<(), FarmError> {
+    let message = target.message(message)?;
+    let speak = target
+        .functions
+        .speak
+        .ok_or_else(|| missing_slot("speak"))?;
+
+    speak(target.animal_ptr, message);
+    resume_animal_panic();
+    Ok(())
+}
+
+impl Farm {
+    /// Creates an empty farm that resolves name clashes with
+    /// `duplicate_policy`.
+    pub fn new(duplicate_policy: DuplicatePolicy) -> Farm {
+        Farm::with_functions(FunctionsMap::empty(), duplicate_policy)
+    }
+
+    pub(crate) fn with_functions(
+        functions_map: FunctionsMap,
+        duplicate_policy: DuplicatePolicy,
+    ) -> Farm {
+        Farm::from_shared(Arc::new(SharedFarm::new(farm::Farm::new(
+            functions_map,
+            duplicate_policy,
+        ))))
+    }
+
+    pub(crate) fn from_shared(shared: Arc<SharedFarm>) -> Farm {
+        Farm { shared }
+    }
+
+    pub(crate) fn into_shared(self) -> Arc<SharedFarm> {
+        self.shared
+    }
+
+    /// Stores `animal` under `name` and reports what happened. An animal
+    /// pushed out by `DuplicatePolicy::Replace` is dropped. Fails with
+    /// `InvalidArgument`, dropping `animal`, if `name` contains a nul byte.
+    pub fn add_animal<A: Animal>(
+        &self,
+        name: &str,
+        animal: A,
+    ) -> Result<AddAnimalResult, FarmError> {
+        let functions_map = FunctionsMap {
+            speak: Some(speak_animal::<A>),
+            drop: Some(drop_animal::<A>),
+            ..FunctionsMap::empty()
+        };
+        let entry = AnimalEntry {
+            functions_map: Some(functions_map),
+            rust_animal: Some(RustAnimal {
+                type_id: TypeId::of::<A>(),
+                retain: retain_animal::<A>,
+                release: release_animal::<A>,
+            }),
+            ..AnimalEntry::new(Arc::into_raw(Arc::new(animal)) as *const c_void)
+        };
+
+        self.add_entry(name, entry)
+    }
+
+    // Stores `entry`, releasing a replaced animal once the lock is dropped so
+    // its `drop` callback may use the farm. If the entry cannot be stored, its
+    // animal is only released when it belongs to the library.
+    pub(crate) fn add_entry(
+        &self,
+        name: &str,
+        entry: AnimalEntry,
+    ) -> Result<AddAnimalResult, FarmError> {
+        // A Rust animal's own map always has `drop`, so it can be released
+        // without asking the farm, which may have been destroyed meanwhile.
+        let rust_animal = entry.rust_animal.is_some().then(|| Evicted {
+            ptr: entry.ptr,
+            drop: entry.functions_map.as_ref().and_then(|map| map.drop),
+        });
+        let added = name_arg(name, "name")
+            .and_then(|()| self.shared.write())
+            .and_then(|mut farm| farm.add_animal(name, entry));
+
+        match added {
+            Ok((mut result, replaced)) => {
+                if let Some(replaced) = replaced {
+                    result.previous_animal = replaced.release();
+                }
+                Ok(result)
+            }
+            Err(error) => {
+                if let Some(rust_animal) = rust_animal {
+                    rust_animal.release();
+                }
+                Err(error)
+            }
+        }
+    }
+
+    /// Returns the animal stored under `name`. Fails with `NotFound` if there
+    /// is none, and with `InvalidArgument` if it is not an `A`.
+    pub fn get_animal<A: Animal>(&self, name: &str) -> Result<Arc<A>, FarmError> {
+        let farm = self.shared.read()?;
+        typed_animal(farm.animal_named(name)?)
+    }
+
+    /// Like `get_animal`, but looks the animal up by its id.
+    pub fn get_animal_by_id<A: Animal>(&self, animal_id: AnimalId) -> Result<Arc<A>, FarmError> {
+        let farm = self.shared.read()?;
+        typed_animal(farm.animal(animal_id)?)
+    }
+
+    pub(crate) fn animal_ptr(&self, name: &str) -> Result<*const c_void, FarmError> {
+        self.shared.read()?.get_animal(name)
+    }
+
+    pub(crate) fn animal_ptr_by_id(&self, animal_id: AnimalId) -> Result<*const c_void, FarmError> {
+        self.shared.read()?.get_animal_by_id(animal_id)
+    }
+
+    /// Returns the id of the animal stored under `name`.
+    pub fn animal_id(&self, name: &str) -> Result<AnimalId, FarmError> {
+        self.shared.read()?.id_of(name)
+    }
+
+    /// Removes the animal stored under `name` and drops it.
+    pub fn remove_animal(&self, name: &str) -> Result<(), FarmError> {
+        self.take_animal(name).map(|_| ())
+    }
+
+    /// Like `remove_animal`, but looks the animal up by its id.
+    pub fn remove_animal_by_id(&self, animal_id: AnimalId) -> Result<(), FarmError> {
+        self.take_animal_by_id(animal_id).map(|_| ())
+    }
+
+    // Removes an animal and frees it with its `drop` callback, or hands its
+    // pointer back if it has none.
+    pub(crate) fn take_animal(&self, name: &str) -> Result<*const c_void, FarmError> {
+        let removed = self.shared.write()?.remove_animal(name)?;
+        Ok(removed.release())
+    }
+
+    pub(crate) fn take_animal_by_id(
+        &self,
+        animal_id: AnimalId,
+    ) -> Result<*const c_void, FarmError> {
+        let removed = self.shared.write()?.remove_animal_by_id(animal_id)?;
+        Ok(removed.release())
+    }
+
+    /// Moves an animal to a new name. Fails with `AlreadyExists` if another
+    /// animal already uses `new_name`, and with `InvalidArgument` if it
+    /// contains a nul byte.
+    pub fn rename_animal(&self, old_name: &str, new_name: &str) -> Result<(), FarmError> {
+        name_arg(new_name, "new_name")?;
+        self.shared.write()?.rename_animal(old_name, new_name)
+    }
+
+    /// How many animals the farm holds.
+    pub fn len(&self) -> usize {
+        // A `Farm` is only destroyed through `destroy_farm`, which takes it.
+        self.shared.read().map_or(0, |farm| farm.animals.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Makes the animal stored under `name` speak `message`.
+    pub fn speak(&self, name: &str, message: &str) -> Result<(), FarmError> {
+        self.speak_raw(name, message_arg(message)?.as_ptr())
+    }
+
+    /// Like `speak`, but looks the animal up by its id.
+    pub fn speak_by_id(&self, animal_id: AnimalId, message: &str) -> Result<(), FarmError> {
+        self.speak_raw_by_id(animal_id, message_arg(message)?.as_ptr())
+    }
+
+    // A null `message` speaks the species' default sound.
+    pub(crate) fn speak_raw(&self, name: &str, message: *const c_char) -> Result<(), FarmError> {
+        let target = self.shared.read()?.speak_target(name)?;
+        speak(target, message)
+    }
+
+    pub(crate) fn speak_raw_by_id(
+        &self,
+        animal_id: AnimalId,
+        message: *const c_char,
+    ) -> Result<(), FarmError> {
+        let target = self.shared.read()?.speak_target_by_id(animal_id)?;
+        speak(target, message)
+    }
+
+    /// Makes every animal speak `message` and returns how many did. Animals
+    /// added through the C API without a `speak` callback are skipped.
+    pub fn speak_all(&self, message: &str) -> Result<usize, FarmError> {
+        self.speak_each(message_arg(message)?.as_ptr(), |_| true)
+    }
+
+    /// Like `speak_all`, but only animals whose name matches `name_pattern`
+    /// speak. In the pattern `*` matches any run of characters, `?` matches
+    /// exactly one, and every other character matches itself.
+    pub fn speak_matching(&self, message: &str, name_pattern: &str) -> Result<usize, FarmError> {
+        self.speak_each(message_arg(message)?.as_ptr(), |visit| {
+            glob::matches(name_pattern, visit.name.to_str().unwrap())
+        })
+    }
+
+    /// Like `speak_all`, but only animals for which `select` returns true
+    /// speak. `select` gets the animal's id and name, and runs just before
+    /// that animal would speak.
+    pub fn speak_where(
+        &self,
+        message: &str,
+        mut select: impl FnMut(AnimalId, &str) -> bool,
+    ) -> Result<usize, FarmError> {
+        self.speak_each(message_arg(message)?.as_ptr(), |visit| {
+            select(visit.animal_id, visit.name.to_str().unwrap())
+        })
+    }
+
+    // Makes every animal picked by `select` speak, visiting the farm the way
+    // `FarmIter` does, and returns how many spoke. Nothing is locked while
+    // `select` or a callback runs.
+    pub(crate) fn speak_each(
+        &self,
+        message: *const c_char,
+        mut select: impl FnMut(&Visit) -> bool,
+    ) -> Result<usize, FarmError> {
+        let mut iter = FarmIter::new(self.shared.clone());
+        let mut spoken = 0;
+
+        while let Some(visit) = iter.next_visit() {
+            if !select(&visit) {
+                continue;
+            }
+            // An earlier callback may have removed the animal since.
+            let Ok(target) = self.shared.read()?.speak_target_by_id(visit.animal_id) else {
+                continue;
+            };
+            if speak(target, message).is_ok() {
+                spoken += 1;
+            }
+        }
+        Ok(spoken)
+    }
+
+    // Like `speak_raw`, but through the animal's `speak_with_reply`. Returns
+    // a copy of the reply for the caller to free, or null for none.
+    pub(crate) fn speak_with_reply_raw(
+        &self,
+        name: &str,
+        message: *const c_char,
+    ) -> Result<*mut c_char, FarmError> {
+        let target = self.shared.read()?.speak_target(name)?;
+        let message = target.message(message)?;
+        let speak_with_reply = target
+            .functions
+            .speak_with_reply
+            .ok_or_else(|| missing_slot("speak_with_reply"))?;
+
+        let host_reply = speak_with_reply(target.animal_ptr, message);
+        if host_reply.is_null() {
+            return Ok(std::ptr::null_mut());
+        }
+
+        let reply = unsafe { CStr::from_ptr(host_reply) }.to_owned();
+        if let Some(free_reply) = target.functions.free_reply {
+            free_reply(host_reply);
+        }
+        Ok(reply.into_raw())
+    }
+
+    // Empties the farm for `destroy_farm`. Animals without a `drop` callback
+    // are handed to `release_animal`, if given.
+    pub(crate) fn destroy(self, release_animal: Option<extern "C" fn(*const c_void)>) {
+        // Only the `destroy_farm` that unregistered the handle gets here, so
+        // the farm is not destroyed yet.
+        let animals = self
+            .shared
+            .write()
+            .map(|mut farm| farm.take_animals())
+            .unwrap_or_default();
+
+        for animal in animals {
+            match (animal.drop, release_animal) {
+                (Some(drop), _) => drop(animal.ptr),
+                (None, Some(release_animal)) => release_animal(animal.ptr),
+                (None, None) => {}
+            }
+        }
+    }
+}
+
+fn typed_animal<A: Animal>(animal: &AnimalEntry) -> Result<Arc<A>, FarmError> {
+    if animal.rust_animal.map(|rust_animal| rust_animal.type_id) != Some(TypeId::of::<A>()) {
+        return Err(FarmError::new(
+            FarmStatus::InvalidArgument,
+            format!(
+                "Animal {} is not a {}",
+                animal.name,
+                std::any::type_name::<A>()
+            ),
+        ));
+    }
+
+    // The farm holds one reference for as long as the animal is stored, and
+    // the caller holds the lock, so the animal is alive here.
+    let animal_ptr = animal.ptr as *const A;
+    unsafe {
+        Arc::increment_strong_count(animal_ptr);
+        Ok(Arc::from_raw(animal_ptr))
+    }
+}
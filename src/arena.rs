@@ -0,0 +1,110 @@
+// Generational arena: values live in reusable slots and are addressed by ids
+// that pack the slot index (low 32 bits) with the slot's generation (high 32
+// bits). A slot's generation changes every time its value is removed, so an id
+// kept after removal never reaches whatever later moves into the slot.
+
+#[derive(Debug)]
+struct Slot<T> {
+    // Never 0, so no id is ever 0.
+    generation: u32,
+    value: Option<T>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Arena<T> {
+    slots: Vec<Slot<T>>,
+    free: Vec<u32>,
+    len: usize,
+}
+
+impl<T> Default for Arena<T> {
+    fn default() -> Self {
+        Arena {
+            slots: Vec::new(),
+            free: Vec::new(),
+            len: 0,
+        }
+    }
+}
+
+fn split(id: u64) -> (usize, u32) {
+    ((id & u32::MAX as u64) as usize, (id >> 32) as u32)
+}
+
+fn join(index: usize, generation: u32) -> u64 {
+    ((generation as u64) << 32) | index as u64
+}
+
+impl<T> Arena<T> {
+    pub fn insert(&mut self, value: T) -> u64 {
+        self.len += 1;
+
+        match self.free.pop() {
+            Some(index) => {
+                let slot = &mut self.slots[index as usize];
+                slot.value = Some(value);
+                join(index as usize, slot.generation)
+            }
+            None => {
+                assert!(self.slots.len() < u32::MAX as usize, "Arena is full");
+                self.slots.push(Slot {
+                    generation: 1,
+                    value: Some(value),
+                });
+                join(self.slots.len() - 1, 1)
+            }
+        }
+    }
+
+    pub fn get(&self, id: u64) -> Option<&T> {
+        let (index, generation) = split(id);
+        self.slots
+            .get(index)
+            .filter(|slot| slot.generation == generation)
+            .and_then(|slot| slot.value.as_ref())
+    }
+
+    pub fn get_mut(&mut self, id: u64) -> Option<&mut T> {
+        let (index, generation) = split(id);
+        self.slots
+            .get_mut(index)
+            .filter(|slot| slot.generation == generation)
+            .and_then(|slot| slot.value.as_mut())
+    }
+
+    pub fn remove(&mut self, id: u64) -> Option<T> {
+        let (index, generation) = split(id);
+        let slot = self
+            .slots
+            .get_mut(index)
+            .filter(|slot| slot.generation == generation)?;
+
+        let value = slot.value.take()?;
+        // A slot that has used up its generations is retired rather than
+        // reused, since wrapping around would bring back ids handed out long
+        // ago.
+        if let Some(generation) = slot.generation.checked_add(1) {
+            slot.generation = generation;
+            self.free.push(index as u32);
+        }
+        self.len -= 1;
+
+        Some(value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u64, &T)> {
+        self.slots.iter().enumerate().filter_map(|(index, slot)| {
+            slot.value
+                .as_ref()
+                .map(|value| (join(index, slot.generation), value))
+        })
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &T> {
+        self.iter().map(|(_, value)| value)
+    }
+}
@@ -0,0 +1,538 @@
+use std::ffi::{c_char, CString};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    farm::AnimalId,
+    handle::{farm_arg, FarmHandle},
+    status::{ffi_guard, str_arg, write_out, FarmError, FarmStatus},
+};
+
+/// The type of an animal attribute's value.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttributeKind {
+    Int = 0,
+    Float,
+    String,
+    Bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Attribute {
+    Int(i64),
+    #[serde(with = "json_float")]
+    Float(f64),
+    String(String),
+    Bool(bool),
+}
+
+impl Attribute {
+    pub fn kind(&self) -> AttributeKind {
+        match self {
+            Attribute::Int(_) => AttributeKind::Int,
+            Attribute::Float(_) => AttributeKind::Float,
+            Attribute::String(_) => AttributeKind::String,
+            Attribute::Bool(_) => AttributeKind::Bool,
+        }
+    }
+}
+
+// JSON has no NaN or infinities, and serde_json writes them as `null`, which
+// would not load back. They are written as the strings "NaN", "inf" and
+// "-inf" instead; finite values stay plain numbers.
+mod json_float {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+        match *value {
+            value if value.is_finite() => serializer.serialize_f64(value),
+            value if value.is_nan() => serializer.serialize_str("NaN"),
+            value if value > 0.0 => serializer.serialize_str("inf"),
+            _ => serializer.serialize_str("-inf"),
+        }
+    }
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Float {
+        Number(f64),
+        Name(String),
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+        let name = match Float::deserialize(deserializer)? {
+            Float::Number(value) => return Ok(value),
+            Float::Name(name) => name,
+        };
+        match name.as_str() {
+            "NaN" => Ok(f64::NAN),
+            "inf" => Ok(f64::INFINITY),
+            "-inf" => Ok(f64::NEG_INFINITY),
+            _ => Err(D::Error::custom(format!("{:?} is not a float", name))),
+        }
+    }
+}
+
+// Tags and attribute keys share these rules.
+unsafe fn label_arg<'a>(ptr: *const c_char, arg_name: &str) -> Result<&'a str, FarmError> {
+    let label = unsafe { str_arg(ptr, arg_name)? };
+    if label.is_empty() {
+        return Err(FarmError::new(
+            FarmStatus::InvalidArgument,
+            format!("`{}` is empty", arg_name),
+        ));
+    }
+    Ok(label)
+}
+
+/// Tags an animal with `tag`. Tagging it twice with the same tag is not an
+/// error.
+///
+/// # Safety
+///
+/// `farm_ptr` must come from `create_farm`, and `animal_name` and `tag` must
+/// be null or nul-terminated C strings.
+#[no_mangle]
+pub unsafe extern "C" fn add_animal_tag(
+    farm_ptr: *const FarmHandle,
+    animal_name: *const c_char,
+    tag: *const c_char,
+) -> FarmStatus {
+    ffi_guard(|| {
+        let farm = farm_arg(farm_ptr)?;
+        let animal_name = unsafe { str_arg(animal_name, "animal_name")? };
+        let tag = unsafe { label_arg(tag, "tag")? };
+
+        farm.write()?
+            .animal_named_mut(animal_name)?
+            .tags
+            .insert(tag.to_string());
+        Ok(())
+    })
+}
+
+/// Removes `tag` from an animal. Fails with `NotFound` if it is not tagged
+/// with it.
+///
+/// # Safety
+///
+/// As for `add_animal_tag`.
+#[no_mangle]
+pub unsafe extern "C" fn remove_animal_tag(
+    farm_ptr: *const FarmHandle,
+    animal_name: *const c_char,
+    tag: *const c_char,
+) -> FarmStatus {
+    ffi_guard(|| {
+        let farm = farm_arg(farm_ptr)?;
+        let animal_name = unsafe { str_arg(animal_name, "animal_name")? };
+        let tag = unsafe { label_arg(tag, "tag")? };
+
+        let removed = farm
+            .write()?
+            .animal_named_mut(animal_name)?
+            .tags
+            .remove(tag);
+        if !removed {
+            return Err(FarmError::new(
+                FarmStatus::NotFound,
+                format!("Animal with name {} has no tag {}", animal_name, tag),
+            ));
+        }
+        Ok(())
+    })
+}
+
+/// Writes whether an animal is tagged with `tag` to `out_has_tag`.
+///
+/// # Safety
+///
+/// As for `add_animal_tag`; `out_has_tag` must also be null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn animal_has_tag(
+    farm_ptr: *const FarmHandle,
+    animal_name: *const c_char,
+    tag: *const c_char,
+    out_has_tag: *mut bool,
+) -> FarmStatus {
+    ffi_guard(|| {
+        let farm = farm_arg(farm_ptr)?;
+        let animal_name = unsafe { str_arg(animal_name, "animal_name")? };
+        let tag = unsafe { label_arg(tag, "tag")? };
+
+        let has_tag = farm.read()?.animal_named(animal_name)?.tags.contains(tag);
+
+        unsafe { write_out(out_has_tag, has_tag, "out_has_tag") }
+    })
+}
+
+/// Writes the ids of every animal tagged with `tag` to `out_ids` and their
+/// number to `out_count`, in the same way as `list_species`.
+///
+/// # Safety
+///
+/// `farm_ptr` must come from `create_farm`, `tag` must be null or a
+/// nul-terminated C string, `out_ids` must be null or valid for writes of
+/// `capacity` ids and `out_count` must be null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn farm_animals_with_tag(
+    farm_ptr: *const FarmHandle,
+    tag: *const c_char,
+    out_ids: *mut AnimalId,
+    capacity: usize,
+    out_count: *mut usize,
+) -> FarmStatus {
+    ffi_guard(|| {
+        let farm = farm_arg(farm_ptr)?;
+        let tag = unsafe { label_arg(tag, "tag")? };
+
+        let animal_ids: Vec<_> = farm
+            .read()?
+            .animals
+            .iter()
+            .filter(|(_, animal)| animal.tags.contains(tag))
+            .map(|(animal_id, _)| animal_id)
+            .collect();
+
+        unsafe { write_ids(&animal_ids, out_ids, capacity, out_count) }
+    })
+}
+
+// Hands a list of ids to the host the way `list_species` does: the count
+// always, the ids only if `out_ids` is not null and big enough.
+pub(crate) unsafe fn write_ids(
+    animal_ids: &[AnimalId],
+    out_ids: *mut AnimalId,
+    capacity: usize,
+    out_count: *mut usize,
+) -> Result<(), FarmError> {
+    unsafe { write_out(out_count, animal_ids.len(), "out_count")? };
+
+    if out_ids.is_null() {
+        return Ok(());
+    }
+    if capacity < animal_ids.len() {
+        return Err(FarmError::new(
+            FarmStatus::BufferTooSmall,
+            format!(
+                "`out_ids` holds {} ids but {} animals matched",
+                capacity,
+                animal_ids.len()
+            ),
+        ));
+    }
+
+    unsafe { std::ptr::copy_nonoverlapping(animal_ids.as_ptr(), out_ids, animal_ids.len()) };
+    Ok(())
+}
+
+unsafe fn set_attribute(
+    farm_ptr: *const FarmHandle,
+    animal_name: *const c_char,
+    key: *const c_char,
+    value: impl FnOnce() -> Result<Attribute, FarmError>,
+) -> FarmStatus {
+    ffi_guard(|| {
+        let farm = farm_arg(farm_ptr)?;
+        let animal_name = unsafe { str_arg(animal_name, "animal_name")? };
+        let key = unsafe { label_arg(key, "key")? };
+        let value = value()?;
+
+        farm.write()?
+            .animal_named_mut(animal_name)?
+            .attributes
+            .insert(key.to_string(), value);
+        Ok(())
+    })
+}
+
+/// Sets an animal's attribute `key` to an integer, replacing any value it
+/// had, whatever its kind.
+///
+/// # Safety
+///
+/// `farm_ptr` must come from `create_farm`, and `animal_name` and `key` must
+/// be null or nul-terminated C strings.
+#[no_mangle]
+pub unsafe extern "C" fn set_animal_attribute_int(
+    farm_ptr: *const FarmHandle,
+    animal_name: *const c_char,
+    key: *const c_char,
+    value: i64,
+) -> FarmStatus {
+    unsafe { set_attribute(farm_ptr, animal_name, key, || Ok(Attribute::Int(value))) }
+}
+
+/// Like `set_animal_attribute_int`, for a floating-point value.
+///
+/// # Safety
+///
+/// As for `set_animal_attribute_int`.
+#[no_mangle]
+pub unsafe extern "C" fn set_animal_attribute_float(
+    farm_ptr: *const FarmHandle,
+    animal_name: *const c_char,
+    key: *const c_char,
+    value: f64,
+) -> FarmStatus {
+    unsafe { set_attribute(farm_ptr, animal_name, key, || Ok(Attribute::Float(value))) }
+}
+
+/// Like `set_animal_attribute_int`, for a string value. The farm keeps its
+/// own copy of `value`.
+///
+/// # Safety
+///
+/// As for `set_animal_attribute_int`; `value` must also be null or a
+/// nul-terminated C string.
+#[no_mangle]
+pub unsafe extern "C" fn set_animal_attribute_string(
+    farm_ptr: *const FarmHandle,
+    animal_name: *const c_char,
+    key: *const c_char,
+    value: *const c_char,
+) -> FarmStatus {
+    unsafe {
+        set_attribute(farm_ptr, animal_name, key, || {
+            Ok(Attribute::String(str_arg(value, "value")?.to_string()))
+        })
+    }
+}
+
+/// Like `set_animal_attribute_int`, for a boolean value.
+///
+/// # Safety
+///
+/// As for `set_animal_attribute_int`.
+#[no_mangle]
+pub unsafe extern "C" fn set_animal_attribute_bool(
+    farm_ptr: *const FarmHandle,
+    animal_name: *const c_char,
+    key: *const c_char,
+    value: bool,
+) -> FarmStatus {
+    unsafe { set_attribute(farm_ptr, animal_name, key, || Ok(Attribute::Bool(value))) }
+}
+
+/// Removes an animal's attribute `key`. Fails with `NotFound` if it has none.
+///
+/// # Safety
+///
+/// As for `set_animal_attribute_int`.
+#[no_mangle]
+pub unsafe extern "C" fn remove_animal_attribute(
+    farm_ptr: *const FarmHandle,
+    animal_name: *const c_char,
+    key: *const c_char,
+) -> FarmStatus {
+    ffi_guard(|| {
+        let farm = farm_arg(farm_ptr)?;
+        let animal_name = unsafe { str_arg(animal_name, "animal_name")? };
+        let key = unsafe { label_arg(key, "key")? };
+
+        farm.write()?
+            .animal_named_mut(animal_name)?
+            .attributes
+            .remove(key)
+            .ok_or_else(|| attribute_not_found(animal_name, key))?;
+        Ok(())
+    })
+}
+
+fn attribute_not_found(animal_name: &str, key: &str) -> FarmError {
+    FarmError::new(
+        FarmStatus::NotFound,
+        format!("Animal with name {} has no attribute {}", animal_name, key),
+    )
+}
+
+// Looks up an attribute and turns it into what the getter hands out, failing
+// with `InvalidArgument` if `convert` rejects its kind.
+unsafe fn get_attribute<T>(
+    farm_ptr: *const FarmHandle,
+    animal_name: *const c_char,
+    key: *const c_char,
+    kind: AttributeKind,
+    convert: impl FnOnce(&Attribute) -> Option<T>,
+    out_value: *mut T,
+) -> FarmStatus {
+    ffi_guard(|| {
+        let farm = farm_arg(farm_ptr)?;
+        let animal_name = unsafe { str_arg(animal_name, "animal_name")? };
+        let key = unsafe { label_arg(key, "key")? };
+        // Checked up front so a string copy is never made just to leak.
+        if out_value.is_null() {
+            return Err(FarmError::new(
+                FarmStatus::NullPointer,
+                "`out_value` is null",
+            ));
+        }
+
+        let value = {
+            let farm = farm.read()?;
+            let attribute = farm
+                .animal_named(animal_name)?
+                .attributes
+                .get(key)
+                .ok_or_else(|| attribute_not_found(animal_name, key))?;
+
+            convert(attribute).ok_or_else(|| {
+                FarmError::new(
+                    FarmStatus::InvalidArgument,
+                    format!(
+                        "Attribute {} of animal {} is {:?}, not {:?}",
+                        key,
+                        animal_name,
+                        attribute.kind(),
+                        kind
+                    ),
+                )
+            })?
+        };
+
+        unsafe { write_out(out_value, value, "out_value") }
+    })
+}
+
+/// Writes the kind of an animal's attribute `key` to `out_kind`.
+///
+/// # Safety
+///
+/// As for `set_animal_attribute_int`; `out_kind` must also be null or
+/// writable.
+#[no_mangle]
+pub unsafe extern "C" fn get_animal_attribute_kind(
+    farm_ptr: *const FarmHandle,
+    animal_name: *const c_char,
+    key: *const c_char,
+    out_kind: *mut AttributeKind,
+) -> FarmStatus {
+    ffi_guard(|| {
+        let farm = farm_arg(farm_ptr)?;
+        let animal_name = unsafe { str_arg(animal_name, "animal_name")? };
+        let key = unsafe { label_arg(key, "key")? };
+
+        let kind = farm
+            .read()?
+            .animal_named(animal_name)?
+            .attributes
+            .get(key)
+            .ok_or_else(|| attribute_not_found(animal_name, key))?
+            .kind();
+
+        unsafe { write_out(out_kind, kind, "out_kind") }
+    })
+}
+
+/// Writes an animal's integer attribute `key` to `out_value`. Fails with
+/// `InvalidArgument` if the attribute holds another kind of value.
+///
+/// # Safety
+///
+/// As for `set_animal_attribute_int`; `out_value` must also be null or
+/// writable.
+#[no_mangle]
+pub unsafe extern "C" fn get_animal_attribute_int(
+    farm_ptr: *const FarmHandle,
+    animal_name: *const c_char,
+    key: *const c_char,
+    out_value: *mut i64,
+) -> FarmStatus {
+    unsafe {
+        get_attribute(
+            farm_ptr,
+            animal_name,
+            key,
+            AttributeKind::Int,
+            |attribute| match attribute {
+                Attribute::Int(value) => Some(*value),
+                _ => None,
+            },
+            out_value,
+        )
+    }
+}
+
+/// Like `get_animal_attribute_int`, for a floating-point attribute.
+///
+/// # Safety
+///
+/// As for `get_animal_attribute_int`.
+#[no_mangle]
+pub unsafe extern "C" fn get_animal_attribute_float(
+    farm_ptr: *const FarmHandle,
+    animal_name: *const c_char,
+    key: *const c_char,
+    out_value: *mut f64,
+) -> FarmStatus {
+    unsafe {
+        get_attribute(
+            farm_ptr,
+            animal_name,
+            key,
+            AttributeKind::Float,
+            |attribute| match attribute {
+                Attribute::Float(value) => Some(*value),
+                _ => None,
+            },
+            out_value,
+        )
+    }
+}
+
+/// Like `get_animal_attribute_int`, for a string attribute. Writes a copy of
+/// the string; free it with `farm_free_string`.
+///
+/// # Safety
+///
+/// As for `get_animal_attribute_int`.
+#[no_mangle]
+pub unsafe extern "C" fn get_animal_attribute_string(
+    farm_ptr: *const FarmHandle,
+    animal_name: *const c_char,
+    key: *const c_char,
+    out_value: *mut *mut c_char,
+) -> FarmStatus {
+    unsafe {
+        get_attribute(
+            farm_ptr,
+            animal_name,
+            key,
+            AttributeKind::String,
+            |attribute| match attribute {
+                Attribute::String(value) => Some(CString::new(value.as_str()).unwrap().into_raw()),
+                _ => None,
+            },
+            out_value,
+        )
+    }
+}
+
+/// Like `get_animal_attribute_int`, for a boolean attribute.
+///
+/// # Safety
+///
+/// As for `get_animal_attribute_int`.
+#[no_mangle]
+pub unsafe extern "C" fn get_animal_attribute_bool(
+    farm_ptr: *const FarmHandle,
+    animal_name: *const c_char,
+    key: *const c_char,
+    out_value: *mut bool,
+) -> FarmStatus {
+    unsafe {
+        get_attribute(
+            farm_ptr,
+            animal_name,
+            key,
+            AttributeKind::Bool,
+            |attribute| match attribute {
+                Attribute::Bool(value) => Some(*value),
+                _ => None,
+            },
+            out_value,
+        )
+    }
+}
@@ -0,0 +1,549 @@
+// Binary encoding of a `FarmSnapshot`, for farms too big to save as JSON.
+//
+// A snapshot is a header followed by sections:
+//
+//     header  = magic "AFRMSNAP", major version u16, minor version u16,
+//               section count u32
+//     section = kind u32, length u64, payload
+//
+// Fixed-size integers are little-endian. Inside payloads, unsigned integers
+// are LEB128 varints, signed ones are zigzag-encoded varints, floats are 8
+// little-endian bytes and strings are indices into the string table section.
+//
+// A minor version only ever adds sections, so readers skip kinds they do not
+// know, unless the kind has `REQUIRED` set. A snapshot with another major
+// version is rejected.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::{
+    attributes::{Attribute, AttributeKind},
+    handle::{farm_arg, FarmHandle},
+    snapshot::{AnimalRecord, FarmSnapshot, SpeciesRecord, SNAPSHOT_VERSION},
+    status::{ffi_guard, write_out, FarmError, FarmStatus},
+};
+
+const MAGIC: &[u8; 8] = b"AFRMSNAP";
+const MAJOR_VERSION: u16 = 1;
+const MINOR_VERSION: u16 = 0;
+
+// Set on section kinds a reader must understand to load the snapshot.
+const REQUIRED: u32 = 1 << 31;
+
+// count, then per string: length, UTF-8 bytes.
+const STRINGS: u32 = 1 | REQUIRED;
+// count, then per species: name, display name, default sound + 1 (0 for
+// none), max count.
+const SPECIES: u32 = 2 | REQUIRED;
+// count, then per animal: name, species index + 1 (0 for none). Later
+// sections refer to animals by their index here.
+const ANIMALS: u32 = 3 | REQUIRED;
+// count, then per tagged animal: animal index, tag count, tags.
+const TAGS: u32 = 4 | REQUIRED;
+// count, then per animal with attributes: animal index, attribute count, and
+// per attribute: key, `AttributeKind` as a byte, value.
+const ATTRIBUTES: u32 = 5 | REQUIRED;
+// count, then per animal with host state: animal index, length, bytes.
+const HOST_STATE: u32 = 6 | REQUIRED;
+
+const SECTIONS: [u32; 6] = [STRINGS, SPECIES, ANIMALS, TAGS, ATTRIBUTES, HOST_STATE];
+
+fn invalid_snapshot(reason: &str) -> FarmError {
+    FarmError::new(
+        FarmStatus::InvalidArgument,
+        format!("Invalid farm snapshot: {}", reason),
+    )
+}
+
+#[derive(Default)]
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.bytes.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.bytes.push(value as u8);
+    }
+
+    fn index(&mut self, index: usize) {
+        self.varint(index as u64);
+    }
+
+    fn signed(&mut self, value: i64) {
+        self.varint(((value << 1) ^ (value >> 63)) as u64);
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.index(bytes.len());
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    fn section(&mut self, kind: u32, payload: Writer) {
+        self.bytes.extend_from_slice(&kind.to_le_bytes());
+        self.bytes
+            .extend_from_slice(&(payload.bytes.len() as u64).to_le_bytes());
+        self.bytes.extend_from_slice(&payload.bytes);
+    }
+}
+
+// Interns every string the snapshot refers to, so each is stored once.
+#[derive(Default)]
+struct StringTable<'a> {
+    strings: Vec<&'a str>,
+    indices: HashMap<&'a str, usize>,
+}
+
+impl<'a> StringTable<'a> {
+    fn index(&mut self, string: &'a str) -> usize {
+        *self.indices.entry(string).or_insert_with(|| {
+            self.strings.push(string);
+            self.strings.len() - 1
+        })
+    }
+}
+
+pub(crate) fn encode(snapshot: &FarmSnapshot) -> Vec<u8> {
+    let mut strings = StringTable::default();
+
+    let mut species = Writer::default();
+    species.index(snapshot.species.len());
+    let mut species_indices = HashMap::new();
+    for (index, record) in snapshot.species.iter().enumerate() {
+        species_indices.insert(record.name.as_str(), index);
+        species.index(strings.index(&record.name));
+        species.index(strings.index(&record.display_name));
+        species.index(
+            record
+                .default_sound
+                .as_deref()
+                .map_or(0, |sound| strings.index(sound) + 1),
+        );
+        species.index(record.max_count);
+    }
+
+    let mut animals = Writer::default();
+    let mut tags = Writer::default();
+    let mut attributes = Writer::default();
+    let mut host_state = Writer::default();
+    let count = |filter: fn(&AnimalRecord) -> bool| {
+        snapshot
+            .animals
+            .iter()
+            .filter(|animal| filter(animal))
+            .count()
+    };
+    animals.index(snapshot.animals.len());
+    tags.index(count(|animal| !animal.tags.is_empty()));
+    attributes.index(count(|animal| !animal.attributes.is_empty()));
+    host_state.index(count(|animal| animal.state.is_some()));
+
+    for (index, animal) in snapshot.animals.iter().enumerate() {
+        animals.index(strings.index(&animal.name));
+        animals.index(
+            animal
+                .species
+                .as_deref()
+                .map_or(0, |name| species_indices[name] + 1),
+        );
+
+        if !animal.tags.is_empty() {
+            tags.index(index);
+            tags.index(animal.tags.len());
+            for tag in &animal.tags {
+                tags.index(strings.index(tag));
+            }
+        }
+
+        if !animal.attributes.is_empty() {
+            attributes.index(index);
+            attributes.index(animal.attributes.len());
+            for (key, value) in &animal.attributes {
+                attributes.index(strings.index(key));
+                attributes.bytes.push(value.kind() as u8);
+                match value {
+                    Attribute::Int(value) => attributes.signed(*value),
+                    Attribute::Float(value) => {
+                        attributes.bytes.extend_from_slice(&value.to_le_bytes())
+                    }
+                    Attribute::String(value) => attributes.index(strings.index(value)),
+                    Attribute::Bool(value) => attributes.bytes.push(*value as u8),
+                }
+            }
+        }
+
+        if let Some(state) = &animal.state {
+            host_state.index(index);
+            host_state.bytes(state.as_bytes());
+        }
+    }
+
+    let mut string_table = Writer::default();
+    string_table.index(strings.strings.len());
+    for string in &strings.strings {
+        string_table.bytes(string.as_bytes());
+    }
+
+    let mut snapshot = Writer::default();
+    snapshot.bytes.extend_from_slice(MAGIC);
+    snapshot
+        .bytes
+        .extend_from_slice(&MAJOR_VERSION.to_le_bytes());
+    snapshot
+        .bytes
+        .extend_from_slice(&MINOR_VERSION.to_le_bytes());
+    snapshot
+        .bytes
+        .extend_from_slice(&(SECTIONS.len() as u32).to_le_bytes());
+    snapshot.section(STRINGS, string_table);
+    snapshot.section(SPECIES, species);
+    snapshot.section(ANIMALS, animals);
+    snapshot.section(TAGS, tags);
+    snapshot.section(ATTRIBUTES, attributes);
+    snapshot.section(HOST_STATE, host_state);
+    snapshot.bytes
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], FarmError> {
+        if self.bytes.len() < len {
+            return Err(invalid_snapshot("unexpected end of data"));
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn fixed<const N: usize>(&mut self) -> Result<[u8; N], FarmError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn byte(&mut self) -> Result<u8, FarmError> {
+        Ok(self.fixed::<1>()?[0])
+    }
+
+    fn varint(&mut self) -> Result<u64, FarmError> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(invalid_snapshot("varint is too long"))
+    }
+
+    fn index(&mut self) -> Result<usize, FarmError> {
+        usize::try_from(self.varint()?).map_err(|_| invalid_snapshot("index is too large"))
+    }
+
+    fn signed(&mut self) -> Result<i64, FarmError> {
+        let value = self.varint()?;
+        Ok((value >> 1) as i64 ^ -((value & 1) as i64))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], FarmError> {
+        let len = self.index()?;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Result<String, FarmError> {
+        let bytes = self.bytes()?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| invalid_snapshot("string is not UTF-8"))
+    }
+
+    // A count of items that each take at least one byte, checked against what
+    // is left so a corrupt count cannot cause a huge allocation.
+    fn count(&mut self) -> Result<usize, FarmError> {
+        let count = self.index()?;
+        if count > self.bytes.len() {
+            return Err(invalid_snapshot("count exceeds the data left"));
+        }
+        Ok(count)
+    }
+}
+
+// Each string reference costs at least a byte of snapshot but can name a long
+// string, so without a limit a small snapshot could decode into a huge farm.
+// The limit is fixed rather than tied to the snapshot's size, since farms
+// sharing long strings between many animals legitimately save to far less
+// than they load into.
+const MAX_STRING_DATA: usize = 1 << 30;
+
+// The string table, and how many more bytes of strings the references into it
+// may still copy out.
+struct Strings {
+    strings: Vec<String>,
+    budget: usize,
+}
+
+impl Strings {
+    // Called before copying a string, so the copy is never made if it would
+    // go over the budget.
+    fn charge(&mut self, len: usize) -> Result<(), FarmError> {
+        self.budget = self
+            .budget
+            .checked_sub(len)
+            .ok_or_else(|| invalid_snapshot("strings expand to too much data"))?;
+        Ok(())
+    }
+
+    fn get(&mut self, index: usize) -> Result<String, FarmError> {
+        let len = self
+            .strings
+            .get(index)
+            .ok_or_else(|| invalid_snapshot("string index out of range"))?
+            .len();
+        self.charge(len)?;
+        Ok(self.strings[index].clone())
+    }
+
+    fn string(&mut self, reader: &mut Reader) -> Result<String, FarmError> {
+        let index = reader.index()?;
+        self.get(index)
+    }
+
+    // A string index + 1, or 0 for none.
+    fn optional_string(&mut self, reader: &mut Reader) -> Result<Option<String>, FarmError> {
+        match reader.index()? {
+            0 => Ok(None),
+            index => self.get(index - 1).map(Some),
+        }
+    }
+}
+
+fn animal_index(animal_count: usize, reader: &mut Reader) -> Result<usize, FarmError> {
+    let index = reader.index()?;
+    if index >= animal_count {
+        return Err(invalid_snapshot("animal index out of range"));
+    }
+    Ok(index)
+}
+
+pub(crate) fn decode(bytes: &[u8]) -> Result<FarmSnapshot, FarmError> {
+    decode_within(bytes, MAX_STRING_DATA)
+}
+
+// Like `decode`, but references may copy at most `max_string_data` bytes of
+// strings out of the string table.
+pub(crate) fn decode_within(
+    bytes: &[u8],
+    max_string_data: usize,
+) -> Result<FarmSnapshot, FarmError> {
+    let mut reader = Reader { bytes };
+    if reader.take(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+        return Err(invalid_snapshot("missing magic number"));
+    }
+    let major_version = u16::from_le_bytes(reader.fixed()?);
+    let _minor_version = u16::from_le_bytes(reader.fixed()?);
+    if major_version != MAJOR_VERSION {
+        return Err(FarmError::new(
+            FarmStatus::NotSupported,
+            format!(
+                "Snapshot format version {} is not supported, only {} is",
+                major_version, MAJOR_VERSION
+            ),
+        ));
+    }
+
+    let section_count = u32::from_le_bytes(reader.fixed()?);
+    let mut sections = HashMap::new();
+    for _ in 0..section_count {
+        let kind = u32::from_le_bytes(reader.fixed()?);
+        let len = usize::try_from(u64::from_le_bytes(reader.fixed()?))
+            .map_err(|_| invalid_snapshot("section is too large"))?;
+        let payload = reader.take(len)?;
+
+        if !SECTIONS.contains(&kind) {
+            if kind & REQUIRED != 0 {
+                return Err(FarmError::new(
+                    FarmStatus::NotSupported,
+                    format!(
+                        "Snapshot has a required section of unknown kind {:#x}",
+                        kind
+                    ),
+                ));
+            }
+            continue;
+        }
+        if sections.insert(kind, payload).is_some() {
+            return Err(invalid_snapshot("section appears twice"));
+        }
+    }
+    let mut section = |kind| Reader {
+        bytes: sections.remove(&kind).unwrap_or_default(),
+    };
+
+    let mut reader = section(STRINGS);
+    let mut strings = Strings {
+        strings: (0..reader.count()?)
+            .map(|_| reader.string())
+            .collect::<Result<Vec<_>, _>>()?,
+        budget: max_string_data,
+    };
+
+    let mut reader = section(SPECIES);
+    let species = (0..reader.count()?)
+        .map(|_| {
+            Ok(SpeciesRecord {
+                name: strings.string(&mut reader)?,
+                display_name: strings.string(&mut reader)?,
+                default_sound: strings.optional_string(&mut reader)?,
+                max_count: reader.index()?,
+            })
+        })
+        .collect::<Result<Vec<_>, FarmError>>()?;
+
+    let mut reader = section(ANIMALS);
+    let mut animals = (0..reader.count()?)
+        .map(|_| {
+            let name = strings.string(&mut reader)?;
+            let species = match reader.index()? {
+                0 => None,
+                index => {
+                    let name = &species
+                        .get(index - 1)
+                        .ok_or_else(|| invalid_snapshot("species index out of range"))?
+                        .name;
+                    strings.charge(name.len())?;
+                    Some(name.clone())
+                }
+            };
+            Ok(AnimalRecord {
+                name,
+                species,
+                tags: BTreeSet::new(),
+                attributes: BTreeMap::new(),
+                state: None,
+            })
+        })
+        .collect::<Result<Vec<_>, FarmError>>()?;
+
+    let mut reader = section(TAGS);
+    for _ in 0..reader.count()? {
+        let animal = animal_index(animals.len(), &mut reader)?;
+        for _ in 0..reader.count()? {
+            let tag = strings.string(&mut reader)?;
+            animals[animal].tags.insert(tag);
+        }
+    }
+
+    let mut reader = section(ATTRIBUTES);
+    for _ in 0..reader.count()? {
+        let animal = animal_index(animals.len(), &mut reader)?;
+        for _ in 0..reader.count()? {
+            let key = strings.string(&mut reader)?;
+            let value = match reader.byte()? {
+                kind if kind == AttributeKind::Int as u8 => Attribute::Int(reader.signed()?),
+                kind if kind == AttributeKind::Float as u8 => {
+                    Attribute::Float(f64::from_le_bytes(reader.fixed()?))
+                }
+                kind if kind == AttributeKind::String as u8 => {
+                    Attribute::String(strings.string(&mut reader)?)
+                }
+                kind if kind == AttributeKind::Bool as u8 => Attribute::Bool(reader.byte()? != 0),
+                _ => return Err(invalid_snapshot("unknown attribute kind")),
+            };
+            animals[animal].attributes.insert(key, value);
+        }
+    }
+
+    let mut reader = section(HOST_STATE);
+    for _ in 0..reader.count()? {
+        let animal = animal_index(animals.len(), &mut reader)?;
+        animals[animal].state = Some(reader.string()?);
+    }
+
+    Ok(FarmSnapshot {
+        version: SNAPSHOT_VERSION,
+        species,
+        animals,
+    })
+}
+
+/// Like `farm_save_json`, but saves the farm in a compact binary format that
+/// is much faster to write and read for large farms. Writes the data to
+/// `out_data` and its size to `out_len`; free it with `farm_free_buffer`.
+///
+/// # Safety
+///
+/// `farm_ptr` must come from `create_farm`, and `out_data` and `out_len` must
+/// be null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn farm_save_binary(
+    farm_ptr: *const FarmHandle,
+    out_data: *mut *mut u8,
+    out_len: *mut usize,
+) -> FarmStatus {
+    ffi_guard(|| {
+        let farm = farm_arg(farm_ptr)?;
+        if out_data.is_null() || out_len.is_null() {
+            return Err(FarmError::new(
+                FarmStatus::NullPointer,
+                "`out_data` or `out_len` is null",
+            ));
+        }
+
+        let snapshot = FarmSnapshot::capture(&farm)?;
+        let data = Box::into_raw(encode(&snapshot).into_boxed_slice());
+
+        unsafe {
+            write_out(out_len, data.len(), "out_len")?;
+            write_out(out_data, data as *mut u8, "out_data")
+        }
+    })
+}
+
+/// Like `farm_load_json`, for data saved by `farm_save_binary`, including by
+/// older versions of this library. Fails with `NotSupported` if the data
+/// comes from a newer version that changed the format incompatibly, and
+/// with `InvalidArgument` if the strings it holds would take more than 1 GiB
+/// once loaded.
+///
+/// # Safety
+///
+/// `farm_ptr` must come from `create_farm`, `data` must be null or valid for
+/// reads of `len` bytes and `out_count` must be null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn farm_load_binary(
+    farm_ptr: *const FarmHandle,
+    data: *const u8,
+    len: usize,
+    out_count: *mut usize,
+) -> FarmStatus {
+    ffi_guard(|| {
+        let farm = farm_arg(farm_ptr)?;
+        if data.is_null() {
+            return Err(FarmError::new(FarmStatus::NullPointer, "`data` is null"));
+        }
+
+        let snapshot = decode(unsafe { std::slice::from_raw_parts(data, len) })?;
+        let count = snapshot.restore(&farm)?;
+
+        if !out_count.is_null() {
+            unsafe { out_count.write(count) };
+        }
+        Ok(())
+    })
+}
+
+/// Frees data handed out by `farm_save_binary`. Null is ignored.
+///
+/// # Safety
+///
+/// `data` must be null or data from `farm_save_binary` that has not been
+/// freed yet, and `len` the size written with it.
+#[no_mangle]
+pub unsafe extern "C" fn farm_free_buffer(data: *mut u8, len: usize) {
+    if !data.is_null() {
+        drop(unsafe { Box::from_raw(std::ptr::slice_from_raw_parts_mut(data, len)) });
+    }
+}
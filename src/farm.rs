@@ -0,0 +1,521 @@
+use std::{
+    any::TypeId,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    ffi::{c_char, c_int},
+    sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+use crate::{
+    arena::Arena,
+    attributes::Attribute,
+    functions_map::{DropFn, FunctionsMap},
+    species::{SpeciesId, SpeciesRegistry},
+    status::{FarmError, FarmStatus},
+};
+
+/// Identifies an animal for as long as it stays in its farm. Once the animal
+/// is removed its id is rejected with `NotFound`, even after the farm reuses
+/// the storage for another animal. 0 is never a valid id.
+pub type AnimalId = u64;
+
+/// What `add_animal` does when the name it is given is already taken.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    /// Fail with `AlreadyExists` and leave the farm unchanged.
+    Reject = 0,
+    /// Store the new animal and hand the previous pointer back to the host.
+    Replace,
+    /// Store the new animal under `<name>#<n>`, using the lowest `n >= 2` that
+    /// is free.
+    KeepBoth,
+}
+
+impl DuplicatePolicy {
+    // Converts the policy a C host passed, which may be any integer.
+    pub(crate) fn from_raw(policy: c_int) -> Result<Self, FarmError> {
+        match policy {
+            policy if policy == DuplicatePolicy::Reject as c_int => Ok(DuplicatePolicy::Reject),
+            policy if policy == DuplicatePolicy::Replace as c_int => Ok(DuplicatePolicy::Replace),
+            policy if policy == DuplicatePolicy::KeepBoth as c_int => Ok(DuplicatePolicy::KeepBoth),
+            _ => Err(FarmError::new(
+                FarmStatus::InvalidArgument,
+                format!("Unknown duplicate policy {}", policy),
+            )),
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddOutcome {
+    Added = 0,
+    Replaced,
+    Suffixed,
+}
+
+/// Reports what `add_animal` did with a new animal.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct AddAnimalResult {
+    pub outcome: AddOutcome,
+    /// The pointer that was stored under the name before, when `outcome` is
+    /// `Replaced` and the farm did not free it with a `drop` callback; null
+    /// otherwise.
+    pub previous_animal: *const std::ffi::c_void,
+    /// The `n` the animal was stored as `<name>#<n>` under, when `outcome` is
+    /// `Suffixed`; 0 otherwise.
+    pub suffix: u32,
+    /// The id the new animal can be looked up by.
+    pub id: AnimalId,
+}
+
+#[derive(Debug)]
+pub(crate) struct AnimalEntry {
+    // Filled in by `Farm::add_animal` with the name it stores the animal under.
+    pub name: String,
+    pub ptr: *const std::ffi::c_void,
+    // Overrides the species' and farm's maps for this animal; slots it leaves
+    // empty still fall back to theirs.
+    pub functions_map: Option<FunctionsMap>,
+    pub species: Option<SpeciesId>,
+    pub tags: BTreeSet<String>,
+    pub attributes: BTreeMap<String, Attribute>,
+    // Set for animals added through `api::Farm::add_animal`, whose pointer
+    // is an `Arc`.
+    pub rust_animal: Option<RustAnimal>,
+}
+
+// The type of a Rust animal's `Arc`, and how to take and give back a
+// reference to it.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RustAnimal {
+    pub type_id: TypeId,
+    pub retain: unsafe fn(*const std::ffi::c_void),
+    pub release: unsafe fn(*const std::ffi::c_void),
+}
+
+// A reference to a Rust animal taken under the lock, so the animal outlives
+// a call made after the lock is dropped even if it is removed meanwhile.
+// Dropped without the lock held, as it may drop the animal.
+pub(crate) struct HeldAnimal {
+    ptr: *const std::ffi::c_void,
+    release: unsafe fn(*const std::ffi::c_void),
+}
+
+impl Drop for HeldAnimal {
+    fn drop(&mut self) {
+        unsafe { (self.release)(self.ptr) };
+    }
+}
+
+impl AnimalEntry {
+    pub fn new(ptr: *const std::ffi::c_void) -> Self {
+        AnimalEntry {
+            name: String::new(),
+            ptr,
+            functions_map: None,
+            species: None,
+            tags: BTreeSet::new(),
+            attributes: BTreeMap::new(),
+            rust_animal: None,
+        }
+    }
+}
+
+// An animal the farm has let go of, along with how to free it. Taken out
+// under the lock and released after it is dropped, so a `drop` callback may
+// call back into the farm.
+#[must_use]
+pub(crate) struct Evicted {
+    pub ptr: *const std::ffi::c_void,
+    pub drop: DropFn,
+}
+
+impl Evicted {
+    // Frees the animal with its `drop` callback and returns null, or hands its
+    // pointer back if it has none.
+    pub fn release(self) -> *const std::ffi::c_void {
+        match self.drop {
+            Some(drop) => {
+                drop(self.ptr);
+                std::ptr::null()
+            }
+            None => self.ptr,
+        }
+    }
+}
+
+// Everything `native_speak` needs to make an animal talk.
+pub(crate) struct SpeakTarget {
+    pub animal_ptr: *const std::ffi::c_void,
+    // The animal's callbacks with every fallback already applied.
+    pub functions: FunctionsMap,
+    pub default_sound: *const c_char,
+    // Keeps a Rust animal alive until the call is over. Host animals are
+    // the host's to keep alive.
+    pub _held: Option<HeldAnimal>,
+}
+
+impl SpeakTarget {
+    // The message to speak: the host's, or the species' default sound if the
+    // host passed null.
+    pub fn message(&self, message: *const c_char) -> Result<*const c_char, FarmError> {
+        match (message.is_null(), self.default_sound.is_null()) {
+            (false, _) => Ok(message),
+            (true, false) => Ok(self.default_sound),
+            (true, true) => Err(FarmError::new(
+                FarmStatus::NullPointer,
+                "`message` is null and the animal's species has no default sound",
+            )),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Farm {
+    pub animals: Arena<AnimalEntry>,
+    // Secondary index over `animals`.
+    pub ids_by_name: HashMap<String, AnimalId>,
+    pub functions_map: FunctionsMap,
+    pub duplicate_policy: DuplicatePolicy,
+    pub species: SpeciesRegistry,
+    // Set once `destroy_farm` has emptied the farm, so calls that looked it
+    // up just before fail with `InvalidHandle` when they take the lock.
+    pub destroyed: bool,
+}
+
+// What a farm handle points to. Every export goes through the lock, so all of
+// them may be called on one farm from many threads. `destroy_farm` only
+// unregisters the handle and empties the farm under the lock; calls that
+// looked the farm up before keep it alive, and `read` and `write` fail for
+// them once it is marked `destroyed`.
+#[derive(Debug)]
+pub(crate) struct SharedFarm {
+    farm: RwLock<Farm>,
+}
+
+// Animal pointers are opaque to the library and never dereferenced by it;
+// hosts that share a farm between threads are responsible for their animals.
+unsafe impl Send for SharedFarm {}
+unsafe impl Sync for SharedFarm {}
+
+// A farm that is dropped without `destroy_farm`, as a Rust `Farm` is, frees
+// the animals left in it with their `drop` callbacks.
+impl Drop for SharedFarm {
+    fn drop(&mut self) {
+        let farm = self.farm.get_mut().unwrap_or_else(PoisonError::into_inner);
+        for animal in farm.take_animals() {
+            animal.release();
+        }
+    }
+}
+
+// A panic while the lock is held has already been reported through
+// `ffi_guard`, so a poisoned lock is used as is rather than failing every
+// later call.
+impl SharedFarm {
+    pub fn new(farm: Farm) -> Self {
+        SharedFarm {
+            farm: RwLock::new(farm),
+        }
+    }
+
+    // Fails with `InvalidHandle` once `destroy_farm` has emptied the farm, so
+    // a call that looked it up just before does not run on the empty farm.
+    pub fn read(&self) -> Result<RwLockReadGuard<'_, Farm>, FarmError> {
+        let farm = self.farm.read().unwrap_or_else(PoisonError::into_inner);
+        live(farm)
+    }
+
+    pub fn write(&self) -> Result<RwLockWriteGuard<'_, Farm>, FarmError> {
+        let farm = self.farm.write().unwrap_or_else(PoisonError::into_inner);
+        live(farm)
+    }
+}
+
+fn live<F: std::ops::Deref<Target = Farm>>(farm: F) -> Result<F, FarmError> {
+    if farm.destroyed {
+        return Err(FarmError::new(
+            FarmStatus::InvalidHandle,
+            "The farm was destroyed while the call was running",
+        ));
+    }
+    Ok(farm)
+}
+
+pub(crate) fn animal_not_found(animal_name: &str) -> FarmError {
+    FarmError::new(
+        FarmStatus::NotFound,
+        format!(
+            "Animal with name {} could not be found in farm",
+            animal_name
+        ),
+    )
+}
+
+pub(crate) fn animal_id_not_found(animal_id: AnimalId) -> FarmError {
+    FarmError::new(
+        FarmStatus::NotFound,
+        format!("Animal with id {} could not be found in farm", animal_id),
+    )
+}
+
+impl Farm {
+    pub fn new(functions_map: FunctionsMap, duplicate_policy: DuplicatePolicy) -> Self {
+        Farm {
+            animals: Arena::default(),
+            ids_by_name: HashMap::new(),
+            functions_map,
+            duplicate_policy,
+            species: SpeciesRegistry::default(),
+            destroyed: false,
+        }
+    }
+
+    pub fn id_of(&self, name: &str) -> Result<AnimalId, FarmError> {
+        self.ids_by_name
+            .get(name)
+            .copied()
+            .ok_or_else(|| animal_not_found(name))
+    }
+
+    pub fn animal(&self, animal_id: AnimalId) -> Result<&AnimalEntry, FarmError> {
+        self.animals
+            .get(animal_id)
+            .ok_or_else(|| animal_id_not_found(animal_id))
+    }
+
+    pub fn animal_named(&self, name: &str) -> Result<&AnimalEntry, FarmError> {
+        self.animal(self.id_of(name)?)
+    }
+
+    pub fn animal_named_mut(&mut self, name: &str) -> Result<&mut AnimalEntry, FarmError> {
+        let animal_id = self.id_of(name)?;
+        Ok(self.animals.get_mut(animal_id).unwrap())
+    }
+
+    // Also returns the animal a `Replace` pushed out, for the caller to
+    // release once the lock is gone.
+    pub fn add_animal(
+        &mut self,
+        name: &str,
+        mut entry: AnimalEntry,
+    ) -> Result<(AddAnimalResult, Option<Evicted>), FarmError> {
+        self.check_species_limit(name, &entry)?;
+
+        let mut result = AddAnimalResult {
+            outcome: AddOutcome::Added,
+            previous_animal: std::ptr::null(),
+            suffix: 0,
+            id: 0,
+        };
+
+        let key = if !self.ids_by_name.contains_key(name) {
+            name.to_string()
+        } else {
+            match self.duplicate_policy {
+                DuplicatePolicy::Reject => {
+                    return Err(FarmError::new(
+                        FarmStatus::AlreadyExists,
+                        format!("Animal with name {} already exists in farm", name),
+                    ));
+                }
+                DuplicatePolicy::Replace => {
+                    result.outcome = AddOutcome::Replaced;
+                    name.to_string()
+                }
+                DuplicatePolicy::KeepBoth => {
+                    let (suffix, suffixed_name) = (2..)
+                        .map(|suffix| (suffix, format!("{}#{}", name, suffix)))
+                        .find(|(_, suffixed_name)| !self.ids_by_name.contains_key(suffixed_name))
+                        .unwrap();
+
+                    result.outcome = AddOutcome::Suffixed;
+                    result.suffix = suffix;
+                    suffixed_name
+                }
+            }
+        };
+
+        let replaced = match self.ids_by_name.get(&key) {
+            Some(&previous_id) => Some(self.remove_animal_by_id(previous_id)?),
+            None => None,
+        };
+
+        self.count_in(&entry);
+        entry.name = key.clone();
+        result.id = self.animals.insert(entry);
+        self.ids_by_name.insert(key, result.id);
+
+        Ok((result, replaced))
+    }
+
+    pub fn get_animal(&self, name: &str) -> Result<*const std::ffi::c_void, FarmError> {
+        self.get_animal_by_id(self.id_of(name)?)
+    }
+
+    pub fn get_animal_by_id(
+        &self,
+        animal_id: AnimalId,
+    ) -> Result<*const std::ffi::c_void, FarmError> {
+        Ok(self.animal(animal_id)?.ptr)
+    }
+
+    pub fn remove_animal(&mut self, name: &str) -> Result<Evicted, FarmError> {
+        self.remove_animal_by_id(self.id_of(name)?)
+    }
+
+    pub fn remove_animal_by_id(&mut self, animal_id: AnimalId) -> Result<Evicted, FarmError> {
+        let animal = self
+            .animals
+            .remove(animal_id)
+            .ok_or_else(|| animal_id_not_found(animal_id))?;
+        self.ids_by_name.remove(&animal.name);
+        self.count_out(&animal);
+
+        Ok(self.evict(&animal))
+    }
+
+    pub fn rename_animal(&mut self, old_name: &str, new_name: &str) -> Result<(), FarmError> {
+        let animal_id = self.id_of(old_name)?;
+        if old_name == new_name {
+            return Ok(());
+        }
+
+        if self.ids_by_name.contains_key(new_name) {
+            return Err(FarmError::new(
+                FarmStatus::AlreadyExists,
+                format!("Animal with name {} already exists in farm", new_name),
+            ));
+        }
+
+        self.ids_by_name.remove(old_name);
+        self.ids_by_name.insert(new_name.to_string(), animal_id);
+        self.animals.get_mut(animal_id).unwrap().name = new_name.to_string();
+        Ok(())
+    }
+
+    // Fails if adding `entry` under `name` would take its species past
+    // `max_count`. An animal it replaces no longer counts.
+    fn check_species_limit(&self, name: &str, entry: &AnimalEntry) -> Result<(), FarmError> {
+        let species_id = match entry.species {
+            Some(species_id) => species_id,
+            None => return Ok(()),
+        };
+        let species = self.species.get(species_id)?;
+
+        let replaces_same_species = self.duplicate_policy == DuplicatePolicy::Replace
+            && self
+                .ids_by_name
+                .get(name)
+                .and_then(|&previous_id| self.animals.get(previous_id))
+                .is_some_and(|previous| previous.species == Some(species_id));
+
+        if species.max_count != 0
+            && species.animal_count >= species.max_count
+            && !replaces_same_species
+        {
+            return Err(FarmError::new(
+                FarmStatus::LimitReached,
+                format!(
+                    "Species {} already has its maximum of {} animals",
+                    species.name.to_str().unwrap(),
+                    species.max_count
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn count_in(&mut self, entry: &AnimalEntry) {
+        if let Some(species) = entry.species.and_then(|id| self.species.get_mut(id).ok()) {
+            species.animal_count += 1;
+        }
+    }
+
+    fn count_out(&mut self, entry: &AnimalEntry) {
+        if let Some(species) = entry.species.and_then(|id| self.species.get_mut(id).ok()) {
+            species.animal_count -= 1;
+        }
+    }
+
+    // The maps an animal's callbacks are looked up in, most specific first:
+    // its own, its species', then the farm's.
+    pub fn functions_maps<'a>(
+        &'a self,
+        animal: &'a AnimalEntry,
+    ) -> impl Iterator<Item = &'a FunctionsMap> {
+        let species_map = animal
+            .species
+            .and_then(|id| self.species.get(id).ok())
+            .and_then(|species| species.functions_map.as_deref());
+
+        animal
+            .functions_map
+            .as_ref()
+            .into_iter()
+            .chain(species_map)
+            .chain(std::iter::once(&self.functions_map))
+    }
+
+    // The animal's callbacks with every fallback applied.
+    pub fn resolved_functions(&self, animal: &AnimalEntry) -> FunctionsMap {
+        self.functions_maps(animal)
+            .copied()
+            .reduce(|resolved, map| resolved.or(&map))
+            .unwrap()
+    }
+
+    pub fn drop_fn(&self, animal: &AnimalEntry) -> DropFn {
+        self.functions_maps(animal).find_map(|map| map.drop)
+    }
+
+    // Empties the farm for `destroy_farm` and marks it destroyed.
+    pub fn take_animals(&mut self) -> Vec<Evicted> {
+        self.destroyed = true;
+
+        self.ids_by_name.clear();
+        let animals = std::mem::take(&mut self.animals);
+        animals.values().map(|animal| self.evict(animal)).collect()
+    }
+
+    pub fn evict(&self, animal: &AnimalEntry) -> Evicted {
+        Evicted {
+            ptr: animal.ptr,
+            drop: self.drop_fn(animal),
+        }
+    }
+
+    pub fn speak_target(&self, name: &str) -> Result<SpeakTarget, FarmError> {
+        self.speak_target_by_id(self.id_of(name)?)
+    }
+
+    pub fn speak_target_by_id(&self, animal_id: AnimalId) -> Result<SpeakTarget, FarmError> {
+        let animal = self.animal(animal_id)?;
+        let functions = self.resolved_functions(animal);
+        let default_sound = animal
+            .species
+            .and_then(|id| self.species.get(id).ok())
+            .and_then(|species| species.default_sound.as_ref())
+            .map_or(std::ptr::null(), |sound| sound.as_ptr());
+
+        // The farm holds a reference for as long as the animal is stored,
+        // and the lock is held, so the animal is alive to take another.
+        let held = animal.rust_animal.map(|rust_animal| {
+            unsafe { (rust_animal.retain)(animal.ptr) };
+            HeldAnimal {
+                ptr: animal.ptr,
+                release: rust_animal.release,
+            }
+        });
+
+        Ok(SpeakTarget {
+            animal_ptr: animal.ptr,
+            functions,
+            default_sound,
+            _held: held,
+        })
+    }
+}
@@ -0,0 +1,133 @@
+use std::{
+    ffi::c_char,
+    mem::{offset_of, size_of},
+};
+
+use crate::status::{FarmError, FarmStatus};
+
+/// The latest `FunctionsMap` layout this library knows about.
+pub const FUNCTIONS_MAP_VERSION: u32 = 4;
+
+pub type SpeakFn = Option<extern "C" fn(*const std::ffi::c_void, *const c_char)>;
+pub type DropFn = Option<extern "C" fn(*const std::ffi::c_void)>;
+pub type SpeakWithReplyFn =
+    Option<extern "C" fn(*const std::ffi::c_void, *const c_char) -> *mut c_char>;
+pub type FreeReplyFn = Option<extern "C" fn(*mut c_char)>;
+pub type SerializeFn = Option<extern "C" fn(*const std::ffi::c_void) -> *mut c_char>;
+pub type DeserializeFn =
+    Option<extern "C" fn(*const c_char, *const c_char) -> *const std::ffi::c_void>;
+
+/// Callbacks the farm uses to drive host animals.
+///
+/// Hosts set `struct_size` to `sizeof(FunctionsMap)` as they compiled it and
+/// `version` to the `FUNCTIONS_MAP_VERSION` they were built against. New slots
+/// are only ever appended, so the library treats any slot past `struct_size`,
+/// like any null slot, as not provided; a slot `struct_size` only partly
+/// covers counts as past it. Which slots a map provides is decided by
+/// `struct_size` alone: `version` is informational and never checked.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct FunctionsMap {
+    pub struct_size: usize,
+    pub version: u32,
+    pub speak: SpeakFn,
+    /// Frees a host animal once the farm lets go of it: when it is removed,
+    /// replaced by `add_animal`, or its farm is destroyed. Since version 2.
+    pub drop: DropFn,
+    /// Like `speak`, but returns what the animal said as a nul-terminated
+    /// string, or null for no reply. Since version 3.
+    pub speak_with_reply: SpeakWithReplyFn,
+    /// Frees a reply returned by `speak_with_reply`, or state returned by
+    /// `serialize`, once the farm has copied it. Left empty, the host keeps
+    /// ownership of those strings. Since version 3.
+    pub free_reply: FreeReplyFn,
+    /// Returns the host state to save with an animal in a farm snapshot, as a
+    /// nul-terminated UTF-8 string, or null for none. Since version 4.
+    pub serialize: SerializeFn,
+    /// Rebuilds a host animal when a snapshot is loaded, from its name and the
+    /// state `serialize` returned (null if there was none), and returns the
+    /// new animal pointer. Since version 4.
+    pub deserialize: DeserializeFn,
+}
+
+const HEADER_SIZE: usize = offset_of!(FunctionsMap, speak);
+// Every slot is one nullable function pointer.
+const SLOT_SIZE: usize = size_of::<SpeakFn>();
+
+impl FunctionsMap {
+    // A current map with every slot empty.
+    pub(crate) fn empty() -> FunctionsMap {
+        FunctionsMap {
+            struct_size: size_of::<FunctionsMap>(),
+            version: FUNCTIONS_MAP_VERSION,
+            speak: None,
+            drop: None,
+            speak_with_reply: None,
+            free_reply: None,
+            serialize: None,
+            deserialize: None,
+        }
+    }
+
+    // Copies the slots a host map provides, leaving the rest empty.
+    pub(crate) unsafe fn from_host(map: *const FunctionsMap) -> Result<FunctionsMap, FarmError> {
+        if map.is_null() {
+            return Err(FarmError::new(
+                FarmStatus::NullPointer,
+                "`functions_map` is null",
+            ));
+        }
+
+        let struct_size = unsafe { (map as *const usize).read_unaligned() };
+        if struct_size < HEADER_SIZE {
+            return Err(FarmError::new(
+                FarmStatus::InvalidArgument,
+                format!(
+                    "`functions_map.struct_size` is {}, smaller than the {} byte header",
+                    struct_size, HEADER_SIZE
+                ),
+            ));
+        }
+
+        // Only whole slots are copied, so a size ending partway through one
+        // can't leave half a function pointer behind.
+        let slots = (struct_size - HEADER_SIZE) / SLOT_SIZE;
+        let copy_size = (HEADER_SIZE + slots * SLOT_SIZE).min(size_of::<FunctionsMap>());
+
+        let mut copy = FunctionsMap {
+            version: 0,
+            ..FunctionsMap::empty()
+        };
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                map as *const u8,
+                &mut copy as *mut FunctionsMap as *mut u8,
+                copy_size,
+            );
+        }
+        copy.struct_size = struct_size;
+
+        Ok(copy)
+    }
+
+    // Fills every slot this map leaves empty from `fallback`.
+    pub(crate) fn or(&self, fallback: &FunctionsMap) -> FunctionsMap {
+        FunctionsMap {
+            struct_size: self.struct_size,
+            version: self.version,
+            speak: self.speak.or(fallback.speak),
+            drop: self.drop.or(fallback.drop),
+            speak_with_reply: self.speak_with_reply.or(fallback.speak_with_reply),
+            free_reply: self.free_reply.or(fallback.free_reply),
+            serialize: self.serialize.or(fallback.serialize),
+            deserialize: self.deserialize.or(fallback.deserialize),
+        }
+    }
+}
+
+pub(crate) fn missing_slot(slot: &str) -> FarmError {
+    FarmError::new(
+        FarmStatus::NotSupported,
+        format!("The farm's `FunctionsMap` does not provide `{}`", slot),
+    )
+}
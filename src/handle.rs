@@ -0,0 +1,120 @@
+use std::sync::{Arc, Mutex};
+
+use crate::{
+    farm::SharedFarm,
+    status::{FarmError, FarmStatus},
+};
+
+/// Opaque handle to a farm, only ever used behind a pointer.
+///
+/// Handle values are tokens rather than addresses: the low half of the bits
+/// picks a slot in the library's farm table and the high half holds that
+/// slot's generation, which changes whenever a farm is destroyed. A handle
+/// that was never issued, belongs to a destroyed farm, or points at a slot
+/// since reused for another farm is rejected with `InvalidHandle`.
+pub struct FarmHandle {
+    _private: [u8; 0],
+}
+
+const INDEX_BITS: u32 = usize::BITS / 2;
+const INDEX_MASK: usize = (1 << INDEX_BITS) - 1;
+const GENERATION_MASK: usize = usize::MAX >> INDEX_BITS;
+
+struct Slot {
+    // Never 0, so no handle is ever null.
+    generation: usize,
+    farm: Option<Arc<SharedFarm>>,
+}
+
+struct FarmTable {
+    slots: Vec<Slot>,
+    free: Vec<usize>,
+}
+
+static FARMS: Mutex<FarmTable> = Mutex::new(FarmTable {
+    slots: Vec::new(),
+    free: Vec::new(),
+});
+
+fn farms() -> std::sync::MutexGuard<'static, FarmTable> {
+    FARMS
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+fn invalid_handle() -> FarmError {
+    FarmError::new(
+        FarmStatus::InvalidHandle,
+        "`farm_ptr` is not a live farm; was it already destroyed?",
+    )
+}
+
+fn split(handle: *const FarmHandle) -> (usize, usize) {
+    let token = handle as usize;
+    (token & INDEX_MASK, token >> INDEX_BITS)
+}
+
+pub(crate) fn register(farm: Arc<SharedFarm>) -> *const FarmHandle {
+    let mut farms = farms();
+    let farm = Some(farm);
+
+    let index = match farms.free.pop() {
+        Some(index) => {
+            farms.slots[index].farm = farm;
+            index
+        }
+        None => {
+            assert!(farms.slots.len() < INDEX_MASK, "Too many live farms");
+            farms.slots.push(Slot {
+                generation: 1,
+                farm,
+            });
+            farms.slots.len() - 1
+        }
+    };
+
+    ((farms.slots[index].generation << INDEX_BITS) | index) as *const FarmHandle
+}
+
+// Empties the handle's slot, invalidating it and every copy of it, and
+// returns the farm it held. Calls that looked the farm up before keep their
+// reference until they finish.
+pub(crate) fn unregister(handle: *const FarmHandle) -> Result<Arc<SharedFarm>, FarmError> {
+    let mut farms = farms();
+    let (index, generation) = split(handle);
+
+    let slot = farms
+        .slots
+        .get_mut(index)
+        .filter(|slot| slot.generation == generation && slot.farm.is_some())
+        .ok_or_else(invalid_handle)?;
+
+    let farm = slot.farm.take().unwrap();
+    // A slot that has used up its generations is retired rather than reused,
+    // since wrapping around would make handles destroyed long ago valid again.
+    if slot.generation < GENERATION_MASK {
+        slot.generation += 1;
+        farms.free.push(index);
+    }
+
+    Ok(farm)
+}
+
+pub(crate) fn farm_arg(handle: *const FarmHandle) -> Result<Arc<SharedFarm>, FarmError> {
+    if handle.is_null() {
+        return Err(FarmError::new(
+            FarmStatus::NullPointer,
+            "`farm_ptr` is null",
+        ));
+    }
+
+    let farms = farms();
+    let (index, generation) = split(handle);
+
+    farms
+        .slots
+        .get(index)
+        .filter(|slot| slot.generation == generation)
+        .and_then(|slot| slot.farm.clone())
+        .ok_or_else(invalid_handle)
+}
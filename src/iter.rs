@@ -0,0 +1,197 @@
+use std::{
+    ffi::{c_char, CString},
+    sync::Arc,
+};
+
+use crate::{
+    farm::{AnimalId, SharedFarm},
+    handle::{farm_arg, FarmHandle},
+    status::{ffi_guard, write_out, FarmError, FarmStatus},
+};
+
+/// Cursor over the animals of a farm, created by `farm_iter_begin`.
+///
+/// The cursor remembers which animals were in the farm when it was created.
+/// Changing the farm while iterating is allowed: animals removed since are
+/// skipped, animals added since are not visited, and renamed animals are
+/// reported under their current name.
+pub struct FarmIter {
+    farm: Arc<SharedFarm>,
+    animal_ids: std::vec::IntoIter<AnimalId>,
+    // Backs the name handed out by the last `farm_iter_next`.
+    current_name: CString,
+}
+
+pub(crate) struct Visit {
+    pub animal_id: AnimalId,
+    pub name: CString,
+    pub animal_ptr: *const std::ffi::c_void,
+}
+
+impl FarmIter {
+    pub(crate) fn new(farm: Arc<SharedFarm>) -> Self {
+        let animal_ids: Vec<_> = farm
+            .read()
+            .map(|farm| farm.animals.iter().map(|(id, _)| id).collect())
+            .unwrap_or_default();
+
+        FarmIter {
+            farm,
+            animal_ids: animal_ids.into_iter(),
+            current_name: CString::default(),
+        }
+    }
+
+    // The lock is only held while looking each animal up, so whatever the
+    // caller does with it may use the farm.
+    pub(crate) fn next_visit(&mut self) -> Option<Visit> {
+        for animal_id in self.animal_ids.by_ref() {
+            if let Ok(animal) = self.farm.read().ok()?.animal(animal_id) {
+                return Some(Visit {
+                    animal_id,
+                    name: CString::new(animal.name.as_str()).unwrap(),
+                    animal_ptr: animal.ptr,
+                });
+            }
+        }
+        None
+    }
+}
+
+/// Writes how many animals the farm holds to `out_count`.
+///
+/// # Safety
+///
+/// `out_count` must be null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn farm_animal_count(
+    farm_ptr: *const FarmHandle,
+    out_count: *mut usize,
+) -> FarmStatus {
+    ffi_guard(|| {
+        let farm = farm_arg(farm_ptr)?;
+
+        let count = farm.read()?.animals.len();
+
+        unsafe { write_out(out_count, count, "out_count") }
+    })
+}
+
+/// Starts iterating over the farm's animals and writes the cursor to
+/// `out_iter`. Free it with `farm_iter_end`; it keeps working, yielding no
+/// more animals, if the farm is destroyed first.
+///
+/// # Safety
+///
+/// `out_iter` must be null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn farm_iter_begin(
+    farm_ptr: *const FarmHandle,
+    out_iter: *mut *mut FarmIter,
+) -> FarmStatus {
+    ffi_guard(|| {
+        let farm = farm_arg(farm_ptr)?;
+
+        if out_iter.is_null() {
+            return Err(FarmError::new(
+                FarmStatus::NullPointer,
+                "`out_iter` is null",
+            ));
+        }
+
+        let iter = Box::into_raw(Box::new(FarmIter::new(farm)));
+
+        unsafe { write_out(out_iter, iter, "out_iter") }
+    })
+}
+
+/// Advances the cursor and writes the next animal's id, name and pointer to
+/// the out-parameters that are not null. Returns `Exhausted` once every
+/// animal has been visited.
+///
+/// The name stays valid until the next call on this cursor.
+///
+/// # Safety
+///
+/// `iter` must come from `farm_iter_begin`, not be freed yet, and not be used
+/// from two threads at once. Each out-parameter must be null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn farm_iter_next(
+    iter: *mut FarmIter,
+    out_id: *mut AnimalId,
+    out_name: *mut *const c_char,
+    out_animal: *mut *const std::ffi::c_void,
+) -> FarmStatus {
+    ffi_guard(|| {
+        if iter.is_null() {
+            return Err(FarmError::new(FarmStatus::NullPointer, "`iter` is null"));
+        }
+        let iter = unsafe { &mut *iter };
+
+        let visit = iter.next_visit().ok_or_else(|| {
+            FarmError::new(FarmStatus::Exhausted, "Every animal has been visited")
+        })?;
+        iter.current_name = visit.name;
+
+        unsafe {
+            if !out_id.is_null() {
+                out_id.write(visit.animal_id);
+            }
+            if !out_name.is_null() {
+                out_name.write(iter.current_name.as_ptr());
+            }
+            if !out_animal.is_null() {
+                out_animal.write(visit.animal_ptr);
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Frees a cursor created by `farm_iter_begin`. Null is ignored.
+///
+/// # Safety
+///
+/// `iter` must be null or come from `farm_iter_begin` and not be freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn farm_iter_end(iter: *mut FarmIter) {
+    if !iter.is_null() {
+        drop(unsafe { Box::from_raw(iter) });
+    }
+}
+
+pub type ForEachAnimalFn = Option<
+    extern "C" fn(*mut std::ffi::c_void, AnimalId, *const c_char, *const std::ffi::c_void) -> bool,
+>;
+
+/// Calls `callback` with `user_data` and each animal's id, name and pointer,
+/// until it returns false or every animal has been visited.
+///
+/// The farm is not locked while `callback` runs, and changes it makes to the
+/// farm follow the same rules as for `FarmIter`. The name is only valid for
+/// the duration of the call.
+#[no_mangle]
+pub extern "C" fn farm_for_each_animal(
+    farm_ptr: *const FarmHandle,
+    callback: ForEachAnimalFn,
+    user_data: *mut std::ffi::c_void,
+) -> FarmStatus {
+    ffi_guard(|| {
+        let farm = farm_arg(farm_ptr)?;
+        let callback = callback
+            .ok_or_else(|| FarmError::new(FarmStatus::NullPointer, "`callback` is null"))?;
+
+        let mut iter = FarmIter::new(farm);
+        while let Some(visit) = iter.next_visit() {
+            if !callback(
+                user_data,
+                visit.animal_id,
+                visit.name.as_ptr(),
+                visit.animal_ptr,
+            ) {
+                break;
+            }
+        }
+        Ok(())
+    })
+}
@@ -1,96 +1,542 @@
-use std::{collections::HashMap, ffi::CStr};
+use std::ffi::{c_char, c_int, CString};
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct FunctionsMap {
-    speak: extern "C" fn(*const std::ffi::c_void, *const i8),
+mod api;
+mod arena;
+mod attributes;
+mod binary_snapshot;
+mod farm;
+mod functions_map;
+mod glob;
+mod handle;
+mod iter;
+mod query;
+mod snapshot;
+mod species;
+mod status;
+
+pub use api::{Animal, Farm};
+pub use attributes::{
+    add_animal_tag, animal_has_tag, farm_animals_with_tag, get_animal_attribute_bool,
+    get_animal_attribute_float, get_animal_attribute_int, get_animal_attribute_kind,
+    get_animal_attribute_string, remove_animal_attribute, remove_animal_tag,
+    set_animal_attribute_bool, set_animal_attribute_float, set_animal_attribute_int,
+    set_animal_attribute_string, AttributeKind,
+};
+pub use binary_snapshot::{farm_free_buffer, farm_load_binary, farm_save_binary};
+use farm::AnimalEntry;
+pub use farm::{AddAnimalResult, AddOutcome, AnimalId, DuplicatePolicy};
+pub use functions_map::{
+    DeserializeFn, DropFn, FreeReplyFn, FunctionsMap, SerializeFn, SpeakFn, SpeakWithReplyFn,
+    FUNCTIONS_MAP_VERSION,
+};
+use handle::farm_arg;
+pub use handle::FarmHandle;
+pub use iter::{
+    farm_animal_count, farm_for_each_animal, farm_iter_begin, farm_iter_end, farm_iter_next,
+    FarmIter, ForEachAnimalFn,
+};
+pub use query::farm_query;
+pub use snapshot::{farm_load_json, farm_save_json};
+pub use species::{
+    find_species, get_species_info, list_species, register_species, species_animal_count,
+    SpeciesId, SpeciesInfo,
+};
+pub use status::{farm_last_error_length, farm_last_error_message, FarmError, FarmStatus};
+use status::{ffi_guard, str_arg, write_out};
+
+unsafe fn write_add_result(
+    result: AddAnimalResult,
+    out_result: *mut AddAnimalResult,
+) -> Result<(), FarmError> {
+    if !out_result.is_null() {
+        unsafe { out_result.write(result) };
+    }
+    Ok(())
 }
 
-#[derive(Debug)]
-struct Farm {
-    pub animal_ptrs: HashMap<String, *const std::ffi::c_void>,
-    pub functions_map: FunctionsMap,
+/// Stores an animal under `animal_name`, resolving a name clash with the
+/// farm's `DuplicatePolicy`. What happened is written to `out_result` unless
+/// it is null.
+///
+/// # Safety
+///
+/// `farm_ptr` must come from `create_farm`, `animal_name` must be null or a
+/// nul-terminated C string and `out_result` must be null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn add_animal(
+    farm_ptr: *const FarmHandle,
+    animal_name: *const c_char,
+    animal_ptr: *const std::ffi::c_void,
+    out_result: *mut AddAnimalResult,
+) -> FarmStatus {
+    ffi_guard(|| {
+        let farm = Farm::from_shared(farm_arg(farm_ptr)?);
+        let animal_name = unsafe { str_arg(animal_name, "animal_name")? };
+
+        let result = farm.add_entry(animal_name, AnimalEntry::new(animal_ptr))?;
+
+        unsafe { write_add_result(result, out_result) }
+    })
 }
 
-impl Farm {
-    pub fn add_animal(&mut self, name: String, animal_ptr: *const std::ffi::c_void) {
-        self.animal_ptrs.insert(name, animal_ptr);
-    }
+/// Like `add_animal`, but the animal dispatches through its own
+/// `functions_map` instead of the farm's. Slots the map leaves empty fall back
+/// to the farm's; a null `functions_map` behaves exactly like `add_animal`.
+///
+/// # Safety
+///
+/// As for `add_animal`; `functions_map` must also be null or point to at least
+/// `functions_map->struct_size` readable bytes. The farm keeps its own copy.
+#[no_mangle]
+pub unsafe extern "C" fn add_animal_with_functions(
+    farm_ptr: *const FarmHandle,
+    animal_name: *const c_char,
+    animal_ptr: *const std::ffi::c_void,
+    functions_map: *const FunctionsMap,
+    out_result: *mut AddAnimalResult,
+) -> FarmStatus {
+    ffi_guard(|| {
+        let farm = Farm::from_shared(farm_arg(farm_ptr)?);
+        let animal_name = unsafe { str_arg(animal_name, "animal_name")? };
+        let functions_map = if functions_map.is_null() {
+            None
+        } else {
+            Some(unsafe { FunctionsMap::from_host(functions_map)? })
+        };
+
+        let entry = AnimalEntry {
+            functions_map,
+            ..AnimalEntry::new(animal_ptr)
+        };
+        let result = farm.add_entry(animal_name, entry)?;
+
+        unsafe { write_add_result(result, out_result) }
+    })
 }
 
+/// Like `add_animal`, but the animal belongs to a species registered with
+/// `register_species` and dispatches through that species' map. Fails with
+/// `LimitReached` if the species already has `max_count` animals.
+///
+/// # Safety
+///
+/// As for `add_animal`.
 #[no_mangle]
-pub extern "C" fn add_animal(
-    farm_ptr: *const std::ffi::c_void,
-    animal_name: *const i8,
+pub unsafe extern "C" fn add_animal_of_species(
+    farm_ptr: *const FarmHandle,
+    animal_name: *const c_char,
     animal_ptr: *const std::ffi::c_void,
-) {
-    let farm = farm_ptr as *mut Farm;
-
-    let animal_name_cstr = unsafe { CStr::from_ptr(animal_name) };
-    let animal_name = match animal_name_cstr.to_str() {
-        Ok(u) => u.to_string(),
-        Err(_) => panic!("Couldn't get CStr for animal name in `add_animal`"),
-    };
-
-    unsafe { (*farm).add_animal(animal_name, animal_ptr); }
-}
-
-#[no_mangle]
-pub extern "C" fn get_animal(
-    farm_ptr: *const std::ffi::c_void,
-    animal_name: *const i8,
-) -> *const std::ffi::c_void {
-    let farm = farm_ptr as *mut Farm;
-
-    let animal_name_cstr = unsafe { CStr::from_ptr(animal_name) };
-    let animal_name = match animal_name_cstr.to_str() {
-        Ok(u) => u.to_string(),
-        Err(_) => panic!("Couldn't get CStr for animal name in `get_animal`"),
-    };
-
-    if let Some(animal_ptr) = unsafe { (*farm).animal_ptrs.get(&animal_name) } {
-        *animal_ptr
-    } else {
-        panic!(
-            "Animal with name {} could not be found in farm",
-            animal_name
-        )
-    }
+    species_id: SpeciesId,
+    out_result: *mut AddAnimalResult,
+) -> FarmStatus {
+    ffi_guard(|| {
+        let farm = Farm::from_shared(farm_arg(farm_ptr)?);
+        let animal_name = unsafe { str_arg(animal_name, "animal_name")? };
+
+        let entry = AnimalEntry {
+            species: Some(species_id),
+            ..AnimalEntry::new(animal_ptr)
+        };
+        let result = farm.add_entry(animal_name, entry)?;
+
+        unsafe { write_add_result(result, out_result) }
+    })
+}
+
+/// Looks up an animal by name and writes its pointer to `out_animal`.
+///
+/// # Safety
+///
+/// `farm_ptr` must come from `create_farm`, `animal_name` must be null or a
+/// nul-terminated C string and `out_animal` must be null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn get_animal(
+    farm_ptr: *const FarmHandle,
+    animal_name: *const c_char,
+    out_animal: *mut *const std::ffi::c_void,
+) -> FarmStatus {
+    ffi_guard(|| {
+        let farm = Farm::from_shared(farm_arg(farm_ptr)?);
+        let animal_name = unsafe { str_arg(animal_name, "animal_name")? };
+
+        let animal_ptr = farm.animal_ptr(animal_name)?;
+
+        unsafe { write_out(out_animal, animal_ptr, "out_animal") }
+    })
+}
+
+/// Like `get_animal`, but looks the animal up by the id `add_animal` gave it.
+///
+/// # Safety
+///
+/// `farm_ptr` must come from `create_farm` and `out_animal` must be null or
+/// writable.
+#[no_mangle]
+pub unsafe extern "C" fn get_animal_by_id(
+    farm_ptr: *const FarmHandle,
+    animal_id: AnimalId,
+    out_animal: *mut *const std::ffi::c_void,
+) -> FarmStatus {
+    ffi_guard(|| {
+        let farm = Farm::from_shared(farm_arg(farm_ptr)?);
+
+        let animal_ptr = farm.animal_ptr_by_id(animal_id)?;
+
+        unsafe { write_out(out_animal, animal_ptr, "out_animal") }
+    })
+}
+
+/// Writes the id of the animal stored under `animal_name` to `out_id`.
+///
+/// # Safety
+///
+/// `farm_ptr` must come from `create_farm`, `animal_name` must be null or a
+/// nul-terminated C string and `out_id` must be null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn get_animal_id(
+    farm_ptr: *const FarmHandle,
+    animal_name: *const c_char,
+    out_id: *mut AnimalId,
+) -> FarmStatus {
+    ffi_guard(|| {
+        let farm = Farm::from_shared(farm_arg(farm_ptr)?);
+        let animal_name = unsafe { str_arg(animal_name, "animal_name")? };
+
+        let animal_id = farm.animal_id(animal_name)?;
+
+        unsafe { write_out(out_id, animal_id, "out_id") }
+    })
+}
+
+/// Removes an animal from the farm. If the animal has a `drop` callback the
+/// farm frees it with that and writes null to `out_animal`; otherwise it writes
+/// the pointer the animal was stored with, handing ownership back to the host.
+///
+/// # Safety
+///
+/// `farm_ptr` must come from `create_farm`, `animal_name` must be null or a
+/// nul-terminated C string and `out_animal` must be null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn remove_animal(
+    farm_ptr: *const FarmHandle,
+    animal_name: *const c_char,
+    out_animal: *mut *const std::ffi::c_void,
+) -> FarmStatus {
+    ffi_guard(|| {
+        let farm = Farm::from_shared(farm_arg(farm_ptr)?);
+        let animal_name = unsafe { str_arg(animal_name, "animal_name")? };
+
+        if out_animal.is_null() {
+            return Err(FarmError::new(
+                FarmStatus::NullPointer,
+                "`out_animal` is null",
+            ));
+        }
+
+        let animal_ptr = farm.take_animal(animal_name)?;
+
+        unsafe { write_out(out_animal, animal_ptr, "out_animal") }
+    })
+}
+
+/// Like `remove_animal`, but looks the animal up by the id `add_animal` gave
+/// it.
+///
+/// # Safety
+///
+/// `farm_ptr` must come from `create_farm` and `out_animal` must be null or
+/// writable.
+#[no_mangle]
+pub unsafe extern "C" fn remove_animal_by_id(
+    farm_ptr: *const FarmHandle,
+    animal_id: AnimalId,
+    out_animal: *mut *const std::ffi::c_void,
+) -> FarmStatus {
+    ffi_guard(|| {
+        let farm = Farm::from_shared(farm_arg(farm_ptr)?);
+
+        if out_animal.is_null() {
+            return Err(FarmError::new(
+                FarmStatus::NullPointer,
+                "`out_animal` is null",
+            ));
+        }
+
+        let animal_ptr = farm.take_animal_by_id(animal_id)?;
+
+        unsafe { write_out(out_animal, animal_ptr, "out_animal") }
+    })
 }
 
+/// Moves an animal to a new name. Fails with `AlreadyExists`, leaving the farm
+/// unchanged, if another animal already uses `new_name`.
+///
+/// # Safety
+///
+/// `farm_ptr` must come from `create_farm`; `old_name` and `new_name` must be
+/// null or nul-terminated C strings.
 #[no_mangle]
-pub extern "C" fn create_farm(functions_map: *mut FunctionsMap) -> *const std::ffi::c_void {
-    let functions_map = unsafe { *Box::from_raw(functions_map) };
+pub unsafe extern "C" fn rename_animal(
+    farm_ptr: *const FarmHandle,
+    old_name: *const c_char,
+    new_name: *const c_char,
+) -> FarmStatus {
+    ffi_guard(|| {
+        let farm = Farm::from_shared(farm_arg(farm_ptr)?);
+        let old_name = unsafe { str_arg(old_name, "old_name")? };
+        let new_name = unsafe { str_arg(new_name, "new_name")? };
 
-    let farm = Farm {
-        animal_ptrs: HashMap::new(),
-        functions_map,
-    };
+        farm.rename_animal(old_name, new_name)
+    })
+}
+
+/// Creates a farm and writes its handle to `out_farm`. `duplicate_policy`, a
+/// `DuplicatePolicy` value, decides how `add_animal` treats names that are
+/// already taken; any other value fails with `InvalidArgument`.
+///
+/// # Safety
+///
+/// `functions_map` must be null or point to at least
+/// `functions_map->struct_size` readable bytes; the farm keeps its own copy.
+/// `out_farm` must be null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn create_farm(
+    functions_map: *const FunctionsMap,
+    duplicate_policy: c_int,
+    out_farm: *mut *const FarmHandle,
+) -> FarmStatus {
+    ffi_guard(|| {
+        let functions_map = unsafe { FunctionsMap::from_host(functions_map)? };
+        let duplicate_policy = DuplicatePolicy::from_raw(duplicate_policy)?;
+
+        if out_farm.is_null() {
+            return Err(FarmError::new(
+                FarmStatus::NullPointer,
+                "`out_farm` is null",
+            ));
+        }
+
+        let farm = Farm::with_functions(functions_map, duplicate_policy);
+        let farm_ptr = handle::register(farm.into_shared());
+
+        unsafe { write_out(out_farm, farm_ptr, "out_farm") }
+    })
+}
+
+/// Destroys a farm created by `create_farm`, invalidating its handle.
+///
+/// Every animal still stored in the farm is freed with its `drop` callback.
+/// For animals without one, `release_animal` is called instead if given, so
+/// the host can free them. The handle is already invalid while these run, so
+/// calls on it from inside them fail with `InvalidHandle`. Returns
+/// `InvalidHandle` without touching anything if `farm_ptr` is not a live
+/// farm, e.g. because it was already destroyed.
+///
+/// Calls racing with this one on other threads either finish first or fail
+/// with `InvalidHandle`; an `add_animal` that loses the race does not store
+/// its animal.
+#[no_mangle]
+pub extern "C" fn destroy_farm(
+    farm_ptr: *const FarmHandle,
+    release_animal: Option<extern "C" fn(*const std::ffi::c_void)>,
+) -> FarmStatus {
+    ffi_guard(|| {
+        if farm_ptr.is_null() {
+            return Err(FarmError::new(
+                FarmStatus::NullPointer,
+                "`farm_ptr` is null",
+            ));
+        }
+
+        let farm = Farm::from_shared(handle::unregister(farm_ptr)?);
+        farm.destroy(release_animal);
+
+        Ok(())
+    })
+}
+
+/// Calls the animal's `speak` with `message`, or with its species'
+/// `default_sound` if `message` is null.
+///
+/// # Safety
+///
+/// `farm_ptr` must come from `create_farm`; `animal_name` and `message` must
+/// be null or nul-terminated C strings.
+#[no_mangle]
+pub unsafe extern "C" fn native_speak(
+    farm_ptr: *const FarmHandle,
+    animal_name: *const c_char,
+    message: *const c_char,
+) -> FarmStatus {
+    ffi_guard(|| {
+        let farm = Farm::from_shared(farm_arg(farm_ptr)?);
+        let animal_name = unsafe { str_arg(animal_name, "animal_name")? };
+
+        farm.speak_raw(animal_name, message)
+    })
+}
+
+/// Like `native_speak`, but looks the animal up by the id `add_animal` gave
+/// it.
+///
+/// # Safety
+///
+/// `farm_ptr` must come from `create_farm` and `message` must be null or a
+/// nul-terminated C string.
+#[no_mangle]
+pub unsafe extern "C" fn native_speak_by_id(
+    farm_ptr: *const FarmHandle,
+    animal_id: AnimalId,
+    message: *const c_char,
+) -> FarmStatus {
+    ffi_guard(|| {
+        let farm = Farm::from_shared(farm_arg(farm_ptr)?);
+
+        farm.speak_raw_by_id(animal_id, message)
+    })
+}
+
+/// Makes every animal in the farm speak `message`, and writes how many did to
+/// `out_spoken` if it is not null.
+///
+/// Animals are visited as by `farm_iter_begin`, so callbacks may change the
+/// farm. Animals that cannot speak, because they have no `speak` callback or
+/// `message` is null and their species has no default sound, are skipped.
+///
+/// # Safety
+///
+/// `farm_ptr` must come from `create_farm`, `message` must be null or a
+/// nul-terminated C string, and `out_spoken` must be null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn native_speak_all(
+    farm_ptr: *const FarmHandle,
+    message: *const c_char,
+    out_spoken: *mut usize,
+) -> FarmStatus {
+    ffi_guard(|| {
+        let farm = Farm::from_shared(farm_arg(farm_ptr)?);
+
+        let spoken = farm.speak_each(message, |_| true)?;
 
-    Box::into_raw(Box::new(farm)) as *const std::ffi::c_void
+        unsafe { write_spoken(out_spoken, spoken) }
+    })
 }
 
+pub type SelectAnimalFn = Option<
+    extern "C" fn(*mut std::ffi::c_void, AnimalId, *const c_char, *const std::ffi::c_void) -> bool,
+>;
+
+/// Like `native_speak_all`, but only animals for which `select` returns true
+/// speak. `select` gets `user_data` and the animal's id, name and pointer,
+/// and runs just before that animal would speak.
+///
+/// # Safety
+///
+/// As for `native_speak_all`.
 #[no_mangle]
-pub extern "C" fn native_speak(
-    farm_ptr: *const std::ffi::c_void,
-    animal_name: *const i8,
-    message: *const i8,
-) {
-    let farm = farm_ptr as *mut Farm;
+pub unsafe extern "C" fn native_speak_where(
+    farm_ptr: *const FarmHandle,
+    message: *const c_char,
+    select: SelectAnimalFn,
+    user_data: *mut std::ffi::c_void,
+    out_spoken: *mut usize,
+) -> FarmStatus {
+    ffi_guard(|| {
+        let farm = Farm::from_shared(farm_arg(farm_ptr)?);
+        let select =
+            select.ok_or_else(|| FarmError::new(FarmStatus::NullPointer, "`select` is null"))?;
 
-    let animal_name_cstr = unsafe { CStr::from_ptr(animal_name) };
-    let animal_name = match animal_name_cstr.to_str() {
-        Ok(u) => u.to_string(),
-        Err(_) => panic!("Couldn't get CStr for animal name in `native_speak`"),
-    };
+        let spoken = farm.speak_each(message, |visit| {
+            select(
+                user_data,
+                visit.animal_id,
+                visit.name.as_ptr(),
+                visit.animal_ptr,
+            )
+        })?;
 
-    if let Some(animal_ptr) = unsafe { (*farm).animal_ptrs.get(&animal_name) } {
-        // TODO: handle not found
-        unsafe { ((*farm).functions_map.speak)(*animal_ptr, message) };
-    } else {
-        panic!(
-            "Animal with name {} could not be found in farm",
-            animal_name
-        )
+        unsafe { write_spoken(out_spoken, spoken) }
+    })
+}
+
+/// Like `native_speak_all`, but only animals whose name matches
+/// `name_pattern` speak. In the pattern `*` matches any run of characters,
+/// `?` matches exactly one, and every other character matches itself.
+///
+/// # Safety
+///
+/// As for `native_speak_all`; `name_pattern` must also be null or a
+/// nul-terminated C string.
+#[no_mangle]
+pub unsafe extern "C" fn native_speak_matching(
+    farm_ptr: *const FarmHandle,
+    message: *const c_char,
+    name_pattern: *const c_char,
+    out_spoken: *mut usize,
+) -> FarmStatus {
+    ffi_guard(|| {
+        let farm = Farm::from_shared(farm_arg(farm_ptr)?);
+        let name_pattern = unsafe { str_arg(name_pattern, "name_pattern")? };
+
+        let spoken = farm.speak_each(message, |visit| {
+            glob::matches(name_pattern, visit.name.to_str().unwrap())
+        })?;
+
+        unsafe { write_spoken(out_spoken, spoken) }
+    })
+}
+
+unsafe fn write_spoken(out_spoken: *mut usize, spoken: usize) -> Result<(), FarmError> {
+    if !out_spoken.is_null() {
+        unsafe { out_spoken.write(spoken) };
     }
+    Ok(())
 }
+
+/// Like `native_speak`, but goes through the animal's `speak_with_reply` and
+/// writes a copy of what it said to `out_reply`, or null if it said nothing.
+/// Free the copy with `farm_free_string`.
+///
+/// # Safety
+///
+/// As for `native_speak`; `out_reply` must also be null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn native_speak_with_reply(
+    farm_ptr: *const FarmHandle,
+    animal_name: *const c_char,
+    message: *const c_char,
+    out_reply: *mut *mut c_char,
+) -> FarmStatus {
+    ffi_guard(|| {
+        let farm = Farm::from_shared(farm_arg(farm_ptr)?);
+        let animal_name = unsafe { str_arg(animal_name, "animal_name")? };
+
+        if out_reply.is_null() {
+            return Err(FarmError::new(
+                FarmStatus::NullPointer,
+                "`out_reply` is null",
+            ));
+        }
+
+        let reply = farm.speak_with_reply_raw(animal_name, message)?;
+
+        unsafe { write_out(out_reply, reply, "out_reply") }
+    })
+}
+
+/// Frees a string handed out by the farm, such as a reply from
+/// `native_speak_with_reply`. Null is ignored.
+///
+/// # Safety
+///
+/// `string` must be null or a string the farm handed out that has not been
+/// freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn farm_free_string(string: *mut c_char) {
+    if !string.is_null() {
+        drop(unsafe { CString::from_raw(string) });
+    }
+}
+
+#[cfg(test)]
+mod tests;
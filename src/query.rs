@@ -0,0 +1,420 @@
+// Filter expressions selecting animals, such as
+//
+//     species == "cow" && age > 3 && tag:barn-2
+//
+// Grammar, loosest binding first:
+//
+//     or         = and ("||" and)*
+//     and        = not ("&&" not)*
+//     not        = "!" not | "(" or ")" | "tag:" label | comparison
+//     comparison = field ("==" | "!=" | "<" | "<=" | ">" | ">=" | "~") value
+//     value      = string | integer | float | "true" | "false"
+//
+// `!` and parentheses nest at most `MAX_DEPTH` deep, so a hostile query can't
+// exhaust the stack while it is parsed, matched or dropped; runs of `&&` and
+// `||` are kept flat and don't count.
+//
+// `name` and `species` (the species' registered name) are built-in fields;
+// any other field is the attribute with that key. `~` matches a string
+// against a name pattern. A comparison whose field is missing, or whose two
+// sides cannot be compared, is false.
+
+use std::{cmp::Ordering, ffi::c_char};
+
+use crate::{
+    attributes::{write_ids, Attribute},
+    farm::{AnimalEntry, AnimalId, Farm},
+    glob,
+    handle::{farm_arg, FarmHandle},
+    status::{ffi_guard, str_arg, FarmError, FarmStatus},
+};
+
+const MAX_DEPTH: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Matches,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    LParen,
+    RParen,
+    And,
+    Or,
+    Not,
+    Compare(CompareOp),
+    Tag(String),
+    Field(String),
+    Value(Attribute),
+}
+
+#[derive(Debug)]
+enum Field {
+    Name,
+    Species,
+    Attribute(String),
+}
+
+#[derive(Debug)]
+enum Query {
+    // Two or more conditions.
+    And(Vec<Query>),
+    Or(Vec<Query>),
+    Not(Box<Query>),
+    Tag(String),
+    Compare(Field, CompareOp, Attribute),
+}
+
+fn invalid_query(offset: usize, reason: &str) -> FarmError {
+    FarmError::new(
+        FarmStatus::InvalidArgument,
+        format!("Invalid query at offset {}: {}", offset, reason),
+    )
+}
+
+fn is_label_char(c: char) -> bool {
+    !c.is_whitespace() && !"()&|!\"".contains(c)
+}
+
+fn is_field_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '.'
+}
+
+struct Lexer<'a> {
+    source: &'a str,
+    offset: usize,
+}
+
+impl Lexer<'_> {
+    fn rest(&self) -> &str {
+        &self.source[self.offset..]
+    }
+
+    fn take_while(&mut self, accept: impl Fn(char) -> bool) -> &str {
+        let start = self.offset;
+        let len = self
+            .rest()
+            .find(|c| !accept(c))
+            .unwrap_or(self.rest().len());
+        self.offset += len;
+        &self.source[start..self.offset]
+    }
+
+    // Reads a double-quoted string; `\"` and `\\` stand for `"` and `\`.
+    fn string(&mut self) -> Result<String, FarmError> {
+        let start = self.offset;
+        let mut value = String::new();
+        let mut chars = self.rest()[1..].char_indices();
+
+        while let Some((index, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.offset += index + 2;
+                    return Ok(value);
+                }
+                '\\' => match chars.next() {
+                    Some((_, c @ ('"' | '\\'))) => value.push(c),
+                    _ => return Err(invalid_query(start + index + 1, "unknown escape")),
+                },
+                c => value.push(c),
+            }
+        }
+        Err(invalid_query(start, "unterminated string"))
+    }
+
+    fn number(&mut self) -> Result<Attribute, FarmError> {
+        let start = self.offset;
+        self.offset += usize::from(self.rest().starts_with('-'));
+        let text = &self.source[start..self.offset];
+        let digits = self.take_while(|c| c.is_ascii_digit() || c == '.');
+        let text = format!("{}{}", text, digits);
+
+        let value = if text.contains('.') {
+            text.parse().map(Attribute::Float).ok()
+        } else {
+            text.parse().map(Attribute::Int).ok()
+        };
+        value.ok_or_else(|| invalid_query(start, "malformed number"))
+    }
+
+    fn tokens(mut self) -> Result<Vec<(usize, Token)>, FarmError> {
+        let mut tokens = Vec::new();
+
+        loop {
+            self.take_while(char::is_whitespace);
+            let start = self.offset;
+            let rest = self.rest();
+            let Some(c) = rest.chars().next() else {
+                return Ok(tokens);
+            };
+
+            let two = |token| (2, Some(token));
+            let one = |token| (1, Some(token));
+            let (len, token) = match c {
+                '(' => one(Token::LParen),
+                ')' => one(Token::RParen),
+                _ if rest.starts_with("&&") => two(Token::And),
+                _ if rest.starts_with("||") => two(Token::Or),
+                _ if rest.starts_with("==") => two(Token::Compare(CompareOp::Eq)),
+                _ if rest.starts_with("!=") => two(Token::Compare(CompareOp::Ne)),
+                _ if rest.starts_with("<=") => two(Token::Compare(CompareOp::Le)),
+                _ if rest.starts_with(">=") => two(Token::Compare(CompareOp::Ge)),
+                '!' => one(Token::Not),
+                '<' => one(Token::Compare(CompareOp::Lt)),
+                '>' => one(Token::Compare(CompareOp::Gt)),
+                '~' => one(Token::Compare(CompareOp::Matches)),
+                _ => (0, None),
+            };
+            if let Some(token) = token {
+                self.offset += len;
+                tokens.push((start, token));
+                continue;
+            }
+
+            let token = if c == '"' {
+                Token::Value(Attribute::String(self.string()?))
+            } else if c == '-' || c.is_ascii_digit() {
+                Token::Value(self.number()?)
+            } else if rest.starts_with("tag:") {
+                self.offset += "tag:".len();
+                let label = if self.rest().starts_with('"') {
+                    self.string()?
+                } else {
+                    self.take_while(is_label_char).to_string()
+                };
+                if label.is_empty() {
+                    return Err(invalid_query(start, "expected a tag after `tag:`"));
+                }
+                Token::Tag(label)
+            } else if is_field_char(c) {
+                match self.take_while(is_field_char) {
+                    "true" => Token::Value(Attribute::Bool(true)),
+                    "false" => Token::Value(Attribute::Bool(false)),
+                    field => Token::Field(field.to_string()),
+                }
+            } else {
+                return Err(invalid_query(start, &format!("unexpected `{}`", c)));
+            };
+            tokens.push((start, token));
+        }
+    }
+}
+
+struct Parser {
+    tokens: std::iter::Peekable<std::vec::IntoIter<(usize, Token)>>,
+    end: usize,
+    // How many `!` and `(` enclose the condition being parsed.
+    depth: usize,
+}
+
+impl Parser {
+    fn offset(&mut self) -> usize {
+        self.tokens.peek().map_or(self.end, |(offset, _)| *offset)
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        let found = self.tokens.peek().is_some_and(|(_, next)| next == token);
+        if found {
+            self.tokens.next();
+        }
+        found
+    }
+
+    fn or(&mut self) -> Result<Query, FarmError> {
+        let mut queries = vec![self.and()?];
+        while self.eat(&Token::Or) {
+            queries.push(self.and()?);
+        }
+        Ok(match queries.len() {
+            1 => queries.pop().unwrap(),
+            _ => Query::Or(queries),
+        })
+    }
+
+    fn and(&mut self) -> Result<Query, FarmError> {
+        let mut queries = vec![self.not()?];
+        while self.eat(&Token::And) {
+            queries.push(self.not()?);
+        }
+        Ok(match queries.len() {
+            1 => queries.pop().unwrap(),
+            _ => Query::And(queries),
+        })
+    }
+
+    // Parses what a `!` or `(` at `offset` encloses, one level deeper.
+    fn nested(
+        &mut self,
+        offset: usize,
+        parse: impl FnOnce(&mut Parser) -> Result<Query, FarmError>,
+    ) -> Result<Query, FarmError> {
+        if self.depth == MAX_DEPTH {
+            return Err(invalid_query(
+                offset,
+                &format!("nested more than {} levels deep", MAX_DEPTH),
+            ));
+        }
+        self.depth += 1;
+        let query = parse(self);
+        self.depth -= 1;
+        query
+    }
+
+    fn not(&mut self) -> Result<Query, FarmError> {
+        let offset = self.offset();
+
+        match self.tokens.next().map(|(_, token)| token) {
+            Some(Token::Not) => {
+                self.nested(offset, |parser| Ok(Query::Not(Box::new(parser.not()?))))
+            }
+            Some(Token::LParen) => self.nested(offset, |parser| {
+                let query = parser.or()?;
+                if !parser.eat(&Token::RParen) {
+                    return Err(invalid_query(parser.offset(), "expected `)`"));
+                }
+                Ok(query)
+            }),
+            Some(Token::Tag(tag)) => Ok(Query::Tag(tag)),
+            Some(Token::Field(field)) => self.comparison(field),
+            _ => Err(invalid_query(offset, "expected a condition")),
+        }
+    }
+
+    fn comparison(&mut self, field: String) -> Result<Query, FarmError> {
+        let field = match field.as_str() {
+            "name" => Field::Name,
+            "species" => Field::Species,
+            _ => Field::Attribute(field),
+        };
+
+        let offset = self.offset();
+        let Some((_, Token::Compare(op))) = self.tokens.next() else {
+            return Err(invalid_query(offset, "expected a comparison operator"));
+        };
+
+        let offset = self.offset();
+        let Some((_, Token::Value(value))) = self.tokens.next() else {
+            return Err(invalid_query(offset, "expected a value"));
+        };
+        if op == CompareOp::Matches && !matches!(value, Attribute::String(_)) {
+            return Err(invalid_query(offset, "`~` needs a string pattern"));
+        }
+
+        Ok(Query::Compare(field, op, value))
+    }
+}
+
+impl Query {
+    pub fn parse(source: &str) -> Result<Query, FarmError> {
+        let tokens = Lexer { source, offset: 0 }.tokens()?;
+        let mut parser = Parser {
+            tokens: tokens.into_iter().peekable(),
+            end: source.len(),
+            depth: 0,
+        };
+
+        let query = parser.or()?;
+        match parser.tokens.next() {
+            None => Ok(query),
+            Some((offset, _)) => Err(invalid_query(offset, "expected `&&`, `||` or the end")),
+        }
+    }
+
+    pub fn matches(&self, farm: &Farm, animal: &AnimalEntry) -> bool {
+        match self {
+            Query::And(queries) => queries.iter().all(|query| query.matches(farm, animal)),
+            Query::Or(queries) => queries.iter().any(|query| query.matches(farm, animal)),
+            Query::Not(query) => !query.matches(farm, animal),
+            Query::Tag(tag) => animal.tags.contains(tag),
+            Query::Compare(field, op, value) => {
+                let species_name = || {
+                    let species = farm.species.get(animal.species?).ok()?;
+                    Some(Attribute::String(species.name.to_str().ok()?.to_string()))
+                };
+                let field_value = match field {
+                    Field::Name => Some(Attribute::String(animal.name.clone())),
+                    Field::Species => species_name(),
+                    Field::Attribute(key) => animal.attributes.get(key).cloned(),
+                };
+                field_value.is_some_and(|field_value| compare(&field_value, *op, value))
+            }
+        }
+    }
+}
+
+fn compare(left: &Attribute, op: CompareOp, right: &Attribute) -> bool {
+    if op == CompareOp::Matches {
+        return match (left, right) {
+            (Attribute::String(name), Attribute::String(pattern)) => glob::matches(pattern, name),
+            _ => false,
+        };
+    }
+
+    let ordering = match (left, right) {
+        (Attribute::Int(left), Attribute::Int(right)) => Some(left.cmp(right)),
+        (Attribute::Int(left), Attribute::Float(right)) => (*left as f64).partial_cmp(right),
+        (Attribute::Float(left), Attribute::Int(right)) => left.partial_cmp(&(*right as f64)),
+        (Attribute::Float(left), Attribute::Float(right)) => left.partial_cmp(right),
+        (Attribute::String(left), Attribute::String(right)) => Some(left.cmp(right)),
+        (Attribute::Bool(left), Attribute::Bool(right)) => Some(left.cmp(right)),
+        _ => None,
+    };
+
+    ordering.is_some_and(|ordering| match op {
+        CompareOp::Eq => ordering == Ordering::Equal,
+        CompareOp::Ne => ordering != Ordering::Equal,
+        CompareOp::Lt => ordering == Ordering::Less,
+        CompareOp::Le => ordering != Ordering::Greater,
+        CompareOp::Gt => ordering == Ordering::Greater,
+        CompareOp::Ge => ordering != Ordering::Less,
+        CompareOp::Matches => unreachable!(),
+    })
+}
+
+/// Writes the ids of every animal matching `query` to `out_ids` and their
+/// number to `out_count`, in the same way as `list_species`. Fails with
+/// `InvalidArgument` if `query` is malformed; the last error message says
+/// where.
+///
+/// A query combines conditions with `&&`, `||`, `!` and parentheses. A
+/// condition is either `tag:<tag>` or a comparison of `name`, `species` or an
+/// attribute key with a string, number or `true`/`false` using `==`, `!=`,
+/// `<`, `<=`, `>`, `>=`, or `~` for a name pattern. Comparisons with a missing
+/// attribute, or of values of different kinds, are false.
+///
+/// # Safety
+///
+/// `farm_ptr` must come from `create_farm`, `query` must be null or a
+/// nul-terminated C string, `out_ids` must be null or valid for writes of
+/// `capacity` ids and `out_count` must be null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn farm_query(
+    farm_ptr: *const FarmHandle,
+    query: *const c_char,
+    out_ids: *mut AnimalId,
+    capacity: usize,
+    out_count: *mut usize,
+) -> FarmStatus {
+    ffi_guard(|| {
+        let farm = farm_arg(farm_ptr)?;
+        let query = Query::parse(unsafe { str_arg(query, "query")? })?;
+
+        let animal_ids: Vec<_> = {
+            let farm = farm.read()?;
+            farm.animals
+                .iter()
+                .filter(|(_, animal)| query.matches(&farm, animal))
+                .map(|(animal_id, _)| animal_id)
+                .collect()
+        };
+
+        unsafe { write_ids(&animal_ids, out_ids, capacity, out_count) }
+    })
+}
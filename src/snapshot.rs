@@ -0,0 +1,371 @@
+// Saving a farm's state and loading it into another farm. Everything the farm
+// owns is captured: species, animal names, tags and attributes. Animal
+// pointers belong to the host, which saves and rebuilds them through the
+// `serialize` and `deserialize` slots of its `FunctionsMap`.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    ffi::{c_char, CStr, CString},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    attributes::Attribute,
+    farm::{AnimalEntry, Evicted, SharedFarm},
+    functions_map::{missing_slot, FreeReplyFn, SerializeFn},
+    handle::{farm_arg, FarmHandle},
+    species::{Species, SpeciesId},
+    status::{ffi_guard, str_arg, write_out, FarmError, FarmStatus},
+};
+
+// Bumped when a change to the format would make older libraries misread
+// snapshots. Fields added with a default don't need a bump: older snapshots
+// load with the default and older libraries ignore fields they don't know.
+pub(crate) const SNAPSHOT_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct FarmSnapshot {
+    pub version: u32,
+    #[serde(default)]
+    pub species: Vec<SpeciesRecord>,
+    #[serde(default)]
+    pub animals: Vec<AnimalRecord>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct SpeciesRecord {
+    pub name: String,
+    pub display_name: String,
+    #[serde(default)]
+    pub default_sound: Option<String>,
+    #[serde(default)]
+    pub max_count: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct AnimalRecord {
+    pub name: String,
+    #[serde(default)]
+    pub species: Option<String>,
+    #[serde(default)]
+    pub tags: BTreeSet<String>,
+    #[serde(default)]
+    pub attributes: BTreeMap<String, Attribute>,
+    // What the host's `serialize` returned.
+    #[serde(default)]
+    pub state: Option<String>,
+}
+
+// What is needed to ask the host for an animal's state once the lock is gone.
+struct PendingState {
+    animal_ptr: *const std::ffi::c_void,
+    serialize: SerializeFn,
+    free_reply: FreeReplyFn,
+}
+
+impl PendingState {
+    fn fetch(self) -> Result<Option<String>, FarmError> {
+        let Some(serialize) = self.serialize else {
+            return Ok(None);
+        };
+        let state = serialize(self.animal_ptr);
+        if state.is_null() {
+            return Ok(None);
+        }
+
+        let copy = unsafe { CStr::from_ptr(state) }
+            .to_str()
+            .map(str::to_string);
+        if let Some(free_reply) = self.free_reply {
+            free_reply(state);
+        }
+        copy.map(Some).map_err(|_| {
+            FarmError::new(
+                FarmStatus::InvalidUtf8,
+                "`serialize` returned a string that is not valid UTF-8",
+            )
+        })
+    }
+}
+
+impl FarmSnapshot {
+    // Copies the farm's state under the lock, then asks the host for each
+    // animal's state without it, so `serialize` may call back into the farm.
+    pub fn capture(farm: &SharedFarm) -> Result<FarmSnapshot, FarmError> {
+        let (species, mut animals, pending) = {
+            let farm = farm.read()?;
+
+            let species = (0..farm.species.len() as SpeciesId)
+                .map(|species_id| {
+                    let species = farm.species.get(species_id).unwrap();
+                    SpeciesRecord {
+                        name: species.name.to_str().unwrap().to_string(),
+                        display_name: species.display_name.to_str().unwrap().to_string(),
+                        default_sound: species
+                            .default_sound
+                            .as_ref()
+                            .map(|sound| sound.to_str().unwrap().to_string()),
+                        max_count: species.max_count,
+                    }
+                })
+                .collect::<Vec<_>>();
+
+            let (animals, pending): (Vec<_>, Vec<_>) = farm
+                .animals
+                .values()
+                .map(|animal| {
+                    let functions = farm.resolved_functions(animal);
+                    let record = AnimalRecord {
+                        name: animal.name.clone(),
+                        species: animal.species.map(|id| species[id as usize].name.clone()),
+                        tags: animal.tags.clone(),
+                        attributes: animal.attributes.clone(),
+                        state: None,
+                    };
+                    let pending = PendingState {
+                        animal_ptr: animal.ptr,
+                        serialize: functions.serialize,
+                        free_reply: functions.free_reply,
+                    };
+                    (record, pending)
+                })
+                .unzip();
+
+            (species, animals, pending)
+        };
+
+        for (animal, pending) in animals.iter_mut().zip(pending) {
+            animal.state = pending.fetch()?;
+        }
+
+        Ok(FarmSnapshot {
+            version: SNAPSHOT_VERSION,
+            species,
+            animals,
+        })
+    }
+
+    // Adds the snapshot's species and animals to `farm`, returning how many
+    // animals were restored. Species the farm already has are kept as they
+    // are; the others are registered without a `FunctionsMap`.
+    //
+    // Animals go through the farm's `DuplicatePolicy` like any other add, and
+    // get new ids. If restoring one fails, the ones before it stay.
+    pub fn restore(self, farm: &SharedFarm) -> Result<usize, FarmError> {
+        if self.version > SNAPSHOT_VERSION {
+            return Err(FarmError::new(
+                FarmStatus::NotSupported,
+                format!(
+                    "Snapshot version {} is newer than the supported version {}",
+                    self.version, SNAPSHOT_VERSION
+                ),
+            ));
+        }
+
+        let species_ids = {
+            let mut farm = farm.write()?;
+            let mut species_ids = HashMap::new();
+            for record in self.species {
+                let name = record.name.clone();
+                let species_id = match farm.species.find(&name) {
+                    Ok(species_id) => species_id,
+                    Err(_) => farm.species.register(record.into_species()?)?,
+                };
+                species_ids.insert(name, species_id);
+            }
+            species_ids
+        };
+
+        let mut restored = 0;
+        for record in self.animals {
+            record.check_labels()?;
+            let species = match &record.species {
+                Some(name) => Some(*species_ids.get(name).ok_or_else(|| {
+                    FarmError::new(
+                        FarmStatus::InvalidArgument,
+                        format!(
+                            "Animal {} belongs to species {}, which the snapshot does not list",
+                            record.name, name
+                        ),
+                    )
+                })?),
+                None => None,
+            };
+            let mut entry = AnimalEntry {
+                species,
+                tags: record.tags,
+                attributes: record.attributes,
+                ..AnimalEntry::new(std::ptr::null())
+            };
+
+            // Looked up together, so an animal that cannot be stored is
+            // freed even if the farm is destroyed meanwhile.
+            let functions = farm.read()?.resolved_functions(&entry);
+            let deserialize = functions
+                .deserialize
+                .ok_or_else(|| missing_slot("deserialize"))?;
+            let name = c_string(&record.name, "animal name")?;
+            let state = record
+                .state
+                .as_deref()
+                .map(|state| c_string(state, "animal state"))
+                .transpose()?;
+            entry.ptr = deserialize(
+                name.as_ptr(),
+                state
+                    .as_ref()
+                    .map_or(std::ptr::null(), |state| state.as_ptr()),
+            );
+
+            let restored_animal = Evicted {
+                ptr: entry.ptr,
+                drop: functions.drop,
+            };
+            let added = farm
+                .write()
+                .and_then(|mut farm| farm.add_animal(&record.name, entry));
+            match added {
+                Ok((_, replaced)) => {
+                    if let Some(replaced) = replaced {
+                        replaced.release();
+                    }
+                }
+                Err(error) => {
+                    restored_animal.release();
+                    return Err(error);
+                }
+            }
+            restored += 1;
+        }
+        Ok(restored)
+    }
+}
+
+fn c_string(value: &str, what: &str) -> Result<CString, FarmError> {
+    CString::new(value).map_err(|_| {
+        FarmError::new(
+            FarmStatus::InvalidArgument,
+            format!("Snapshot has a {} containing a nul byte", what),
+        )
+    })
+}
+
+// Tags and attribute keys must be non-empty, as `label_arg` requires, and
+// neither they nor string values may hold a nul byte, so they can be handed
+// back to the host as C strings.
+fn label(value: &str, what: &str) -> Result<(), FarmError> {
+    if value.is_empty() {
+        return Err(FarmError::new(
+            FarmStatus::InvalidArgument,
+            format!("Snapshot has an empty {}", what),
+        ));
+    }
+    c_string(value, what).map(|_| ())
+}
+
+impl AnimalRecord {
+    fn check_labels(&self) -> Result<(), FarmError> {
+        for tag in &self.tags {
+            label(tag, "tag")?;
+        }
+        for (key, value) in &self.attributes {
+            label(key, "attribute key")?;
+            if let Attribute::String(value) = value {
+                c_string(value, "string attribute")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SpeciesRecord {
+    fn into_species(self) -> Result<Species, FarmError> {
+        Ok(Species {
+            name: c_string(&self.name, "species name")?,
+            display_name: c_string(&self.display_name, "species display name")?,
+            default_sound: self
+                .default_sound
+                .as_deref()
+                .map(|sound| c_string(sound, "default sound"))
+                .transpose()?,
+            max_count: self.max_count,
+            functions_map: None,
+            animal_count: 0,
+        })
+    }
+}
+
+/// Saves the farm's species and animals as JSON and writes the document to
+/// `out_json`; free it with `farm_free_string`.
+///
+/// Animals are saved with their name, species, tags and attributes, plus
+/// whatever the host's `serialize` callback returns for them. Their ids and
+/// `FunctionsMap`s are not saved. NaN and infinite float attributes are saved
+/// as the strings "NaN", "inf" and "-inf".
+///
+/// # Safety
+///
+/// `farm_ptr` must come from `create_farm` and `out_json` must be null or
+/// writable.
+#[no_mangle]
+pub unsafe extern "C" fn farm_save_json(
+    farm_ptr: *const FarmHandle,
+    out_json: *mut *mut c_char,
+) -> FarmStatus {
+    ffi_guard(|| {
+        let farm = farm_arg(farm_ptr)?;
+        if out_json.is_null() {
+            return Err(FarmError::new(
+                FarmStatus::NullPointer,
+                "`out_json` is null",
+            ));
+        }
+
+        let snapshot = FarmSnapshot::capture(&farm)?;
+        let json = serde_json::to_string(&snapshot).unwrap();
+
+        unsafe { write_out(out_json, CString::new(json).unwrap().into_raw(), "out_json") }
+    })
+}
+
+/// Loads a document made by `farm_save_json` into the farm, rebuilding each
+/// animal with the host's `deserialize` callback, and writes how many
+/// animals were loaded to `out_count` if it is not null.
+///
+/// Species the farm lacks are registered without a `FunctionsMap`; ones it
+/// already has, looked up by name, are used as they are. Animals are added
+/// as by `add_animal`, so name clashes follow the farm's `DuplicatePolicy`,
+/// and get new ids. If an animal cannot be added, it is dropped, loading
+/// stops with that error and the animals loaded before it stay in the farm.
+/// Fails with `InvalidArgument` if `json` is not a snapshot, and with
+/// `NotSupported` if it comes from a newer, incompatible library.
+///
+/// # Safety
+///
+/// `farm_ptr` must come from `create_farm`, `json` must be null or a
+/// nul-terminated C string and `out_count` must be null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn farm_load_json(
+    farm_ptr: *const FarmHandle,
+    json: *const c_char,
+    out_count: *mut usize,
+) -> FarmStatus {
+    ffi_guard(|| {
+        let farm = farm_arg(farm_ptr)?;
+        let json = unsafe { str_arg(json, "json")? };
+
+        let snapshot: FarmSnapshot = serde_json::from_str(json).map_err(|error| {
+            FarmError::new(
+                FarmStatus::InvalidArgument,
+                format!("Invalid farm snapshot: {}", error),
+            )
+        })?;
+        let count = snapshot.restore(&farm)?;
+
+        if !out_count.is_null() {
+            unsafe { out_count.write(count) };
+        }
+        Ok(())
+    })
+}
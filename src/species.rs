@@ -0,0 +1,292 @@
+use std::{
+    collections::HashMap,
+    ffi::{c_char, CString},
+};
+
+use crate::{
+    functions_map::FunctionsMap,
+    handle::{farm_arg, FarmHandle},
+    status::{ffi_guard, str_arg, write_out, FarmError, FarmStatus},
+};
+
+pub type SpeciesId = u32;
+
+/// Describes a species to `register_species`, and is filled in by
+/// `get_species_info`.
+///
+/// Strings and the map returned by `get_species_info` are owned by the farm
+/// and stay valid until it is destroyed.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SpeciesInfo {
+    pub name: *const c_char,
+    /// Null when registering means "same as `name`".
+    pub display_name: *const c_char,
+    /// What the species says when `native_speak` is given a null message. May
+    /// be null.
+    pub default_sound: *const c_char,
+    /// The most animals of this species the farm will hold, or 0 for no limit.
+    pub max_count: usize,
+    /// Callbacks for animals of this species. Null, like any slot left empty,
+    /// falls back to the farm's map.
+    pub functions_map: *const FunctionsMap,
+}
+
+#[derive(Debug)]
+pub(crate) struct Species {
+    pub name: CString,
+    pub display_name: CString,
+    pub default_sound: Option<CString>,
+    pub max_count: usize,
+    // Boxed so the pointer `get_species_info` hands out stays put as the
+    // registry grows.
+    pub functions_map: Option<Box<FunctionsMap>>,
+    pub animal_count: usize,
+}
+
+impl Species {
+    fn info(&self) -> SpeciesInfo {
+        SpeciesInfo {
+            name: self.name.as_ptr(),
+            display_name: self.display_name.as_ptr(),
+            default_sound: self
+                .default_sound
+                .as_ref()
+                .map_or(std::ptr::null(), |sound| sound.as_ptr()),
+            max_count: self.max_count,
+            functions_map: self
+                .functions_map
+                .as_deref()
+                .map_or(std::ptr::null(), |map| map as *const FunctionsMap),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct SpeciesRegistry {
+    species: Vec<Species>,
+    ids_by_name: HashMap<String, SpeciesId>,
+}
+
+pub(crate) fn species_not_found(species_id: SpeciesId) -> FarmError {
+    FarmError::new(
+        FarmStatus::NotFound,
+        format!("Species with id {} is not registered in farm", species_id),
+    )
+}
+
+impl SpeciesRegistry {
+    pub fn register(&mut self, species: Species) -> Result<SpeciesId, FarmError> {
+        let name = species.name.to_str().unwrap().to_string();
+        if self.ids_by_name.contains_key(&name) {
+            return Err(FarmError::new(
+                FarmStatus::AlreadyExists,
+                format!("Species {} is already registered in farm", name),
+            ));
+        }
+
+        let species_id = self.species.len() as SpeciesId;
+        self.species.push(species);
+        self.ids_by_name.insert(name, species_id);
+
+        Ok(species_id)
+    }
+
+    pub fn get(&self, species_id: SpeciesId) -> Result<&Species, FarmError> {
+        self.species
+            .get(species_id as usize)
+            .ok_or_else(|| species_not_found(species_id))
+    }
+
+    pub fn get_mut(&mut self, species_id: SpeciesId) -> Result<&mut Species, FarmError> {
+        self.species
+            .get_mut(species_id as usize)
+            .ok_or_else(|| species_not_found(species_id))
+    }
+
+    pub fn find(&self, name: &str) -> Result<SpeciesId, FarmError> {
+        self.ids_by_name.get(name).copied().ok_or_else(|| {
+            FarmError::new(
+                FarmStatus::NotFound,
+                format!("Species {} is not registered in farm", name),
+            )
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.species.len()
+    }
+}
+
+unsafe fn optional_str_arg<'a>(
+    ptr: *const c_char,
+    arg_name: &str,
+) -> Result<Option<&'a str>, FarmError> {
+    if ptr.is_null() {
+        Ok(None)
+    } else {
+        unsafe { str_arg(ptr, arg_name) }.map(Some)
+    }
+}
+
+/// Registers a species and writes its id to `out_species_id`. Fails with
+/// `AlreadyExists` if the farm already has a species with the same name.
+///
+/// # Safety
+///
+/// `farm_ptr` must come from `create_farm` and `info` must be null or valid,
+/// with each string null or nul-terminated and `functions_map` null or
+/// pointing to at least `functions_map->struct_size` readable bytes. The farm
+/// copies everything it needs. `out_species_id` must be null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn register_species(
+    farm_ptr: *const FarmHandle,
+    info: *const SpeciesInfo,
+    out_species_id: *mut SpeciesId,
+) -> FarmStatus {
+    ffi_guard(|| {
+        let farm = farm_arg(farm_ptr)?;
+        if info.is_null() {
+            return Err(FarmError::new(FarmStatus::NullPointer, "`info` is null"));
+        }
+        if out_species_id.is_null() {
+            return Err(FarmError::new(
+                FarmStatus::NullPointer,
+                "`out_species_id` is null",
+            ));
+        }
+
+        let info = unsafe { &*info };
+        let name = unsafe { str_arg(info.name, "info.name")? };
+        let display_name = unsafe { optional_str_arg(info.display_name, "info.display_name")? };
+        let default_sound = unsafe { optional_str_arg(info.default_sound, "info.default_sound")? };
+        let functions_map = if info.functions_map.is_null() {
+            None
+        } else {
+            Some(Box::new(unsafe {
+                FunctionsMap::from_host(info.functions_map)?
+            }))
+        };
+
+        let species = Species {
+            name: CString::new(name).unwrap(),
+            display_name: CString::new(display_name.unwrap_or(name)).unwrap(),
+            default_sound: default_sound.map(|sound| CString::new(sound).unwrap()),
+            max_count: info.max_count,
+            functions_map,
+            animal_count: 0,
+        };
+        let species_id = farm.write()?.species.register(species)?;
+
+        unsafe { write_out(out_species_id, species_id, "out_species_id") }
+    })
+}
+
+/// Writes the id of the species registered under `name` to `out_species_id`.
+///
+/// # Safety
+///
+/// `farm_ptr` must come from `create_farm`, `name` must be null or a
+/// nul-terminated C string and `out_species_id` must be null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn find_species(
+    farm_ptr: *const FarmHandle,
+    name: *const c_char,
+    out_species_id: *mut SpeciesId,
+) -> FarmStatus {
+    ffi_guard(|| {
+        let farm = farm_arg(farm_ptr)?;
+        let name = unsafe { str_arg(name, "name")? };
+
+        let species_id = farm.read()?.species.find(name)?;
+
+        unsafe { write_out(out_species_id, species_id, "out_species_id") }
+    })
+}
+
+/// Writes the ids of every registered species to `out_ids` and their number
+/// to `out_count`.
+///
+/// Pass a null `out_ids` to only query the number. Fails with
+/// `BufferTooSmall`, after still writing `out_count`, if `capacity` cannot hold
+/// every id.
+///
+/// # Safety
+///
+/// `farm_ptr` must come from `create_farm`, `out_ids` must be null or valid for
+/// writes of `capacity` ids and `out_count` must be null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn list_species(
+    farm_ptr: *const FarmHandle,
+    out_ids: *mut SpeciesId,
+    capacity: usize,
+    out_count: *mut usize,
+) -> FarmStatus {
+    ffi_guard(|| {
+        let farm = farm_arg(farm_ptr)?;
+        let count = farm.read()?.species.len();
+
+        unsafe { write_out(out_count, count, "out_count")? };
+
+        if out_ids.is_null() {
+            return Ok(());
+        }
+        if capacity < count {
+            return Err(FarmError::new(
+                FarmStatus::BufferTooSmall,
+                format!(
+                    "`out_ids` holds {} ids but the farm has {} species",
+                    capacity, count
+                ),
+            ));
+        }
+
+        for species_id in 0..count {
+            unsafe { out_ids.add(species_id).write(species_id as SpeciesId) };
+        }
+        Ok(())
+    })
+}
+
+/// Writes the description of a registered species to `out_info`.
+///
+/// # Safety
+///
+/// `farm_ptr` must come from `create_farm` and `out_info` must be null or
+/// writable.
+#[no_mangle]
+pub unsafe extern "C" fn get_species_info(
+    farm_ptr: *const FarmHandle,
+    species_id: SpeciesId,
+    out_info: *mut SpeciesInfo,
+) -> FarmStatus {
+    ffi_guard(|| {
+        let farm = farm_arg(farm_ptr)?;
+
+        let info = farm.read()?.species.get(species_id)?.info();
+
+        unsafe { write_out(out_info, info, "out_info") }
+    })
+}
+
+/// Writes how many animals of a species the farm currently holds to
+/// `out_count`.
+///
+/// # Safety
+///
+/// `farm_ptr` must come from `create_farm` and `out_count` must be null or
+/// writable.
+#[no_mangle]
+pub unsafe extern "C" fn species_animal_count(
+    farm_ptr: *const FarmHandle,
+    species_id: SpeciesId,
+    out_count: *mut usize,
+) -> FarmStatus {
+    ffi_guard(|| {
+        let farm = farm_arg(farm_ptr)?;
+
+        let count = farm.read()?.species.get(species_id)?.animal_count;
+
+        unsafe { write_out(out_count, count, "out_count") }
+    })
+}
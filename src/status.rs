@@ -0,0 +1,169 @@
+use std::{
+    any::Any,
+    cell::RefCell,
+    ffi::{c_char, CStr},
+    fmt,
+    panic::{catch_unwind, AssertUnwindSafe},
+};
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FarmStatus {
+    Ok = 0,
+    NullPointer,
+    InvalidUtf8,
+    NotFound,
+    AlreadyExists,
+    InvalidHandle,
+    Panic,
+    BufferTooSmall,
+    InvalidArgument,
+    NotSupported,
+    LimitReached,
+    Exhausted,
+}
+
+/// Why a call on a `Farm` failed: the status the C API reports for it, and
+/// the message `farm_last_error_message` would return.
+#[derive(Debug)]
+pub struct FarmError {
+    pub(crate) status: FarmStatus,
+    pub(crate) message: String,
+}
+
+impl FarmError {
+    pub(crate) fn new(status: FarmStatus, message: impl Into<String>) -> Self {
+        FarmError {
+            status,
+            message: message.into(),
+        }
+    }
+
+    pub fn status(&self) -> FarmStatus {
+        self.status
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for FarmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for FarmError {}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        format!("Panicked: {}", message)
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        format!("Panicked: {}", message)
+    } else {
+        "Panicked with a non-string payload".to_string()
+    }
+}
+
+// Runs the body of an exported function, turning an `Err` into its status and
+// a Rust panic into `FarmStatus::Panic` so nothing unwinds into the host. The
+// message of either is kept as this thread's last error.
+pub(crate) fn ffi_guard<F>(body: F) -> FarmStatus
+where
+    F: FnOnce() -> Result<(), FarmError>,
+{
+    match catch_unwind(AssertUnwindSafe(body)) {
+        Ok(Ok(())) => FarmStatus::Ok,
+        Ok(Err(error)) => {
+            set_last_error(error.message);
+            error.status
+        }
+        Err(payload) => {
+            set_last_error(panic_message(payload.as_ref()));
+            FarmStatus::Panic
+        }
+    }
+}
+
+pub(crate) unsafe fn str_arg<'a>(ptr: *const c_char, arg_name: &str) -> Result<&'a str, FarmError> {
+    if ptr.is_null() {
+        return Err(FarmError::new(
+            FarmStatus::NullPointer,
+            format!("`{}` is null", arg_name),
+        ));
+    }
+
+    unsafe { CStr::from_ptr(ptr) }.to_str().map_err(|_| {
+        FarmError::new(
+            FarmStatus::InvalidUtf8,
+            format!("Couldn't get CStr for `{}`: not valid UTF-8", arg_name),
+        )
+    })
+}
+
+pub(crate) unsafe fn write_out<T>(out: *mut T, value: T, arg_name: &str) -> Result<(), FarmError> {
+    if out.is_null() {
+        return Err(FarmError::new(
+            FarmStatus::NullPointer,
+            format!("`{}` is null", arg_name),
+        ));
+    }
+
+    unsafe { out.write(value) };
+    Ok(())
+}
+
+/// Returns the size in bytes, including the nul terminator, of the message
+/// describing the last error on the calling thread, or 0 if there is none.
+#[no_mangle]
+pub extern "C" fn farm_last_error_length() -> usize {
+    catch_unwind(|| LAST_ERROR.with(|last| last.borrow().as_ref().map_or(0, |m| m.len() + 1)))
+        .unwrap_or(0)
+}
+
+/// Copies the message describing the last error on the calling thread into
+/// `buffer` as a nul-terminated string.
+///
+/// Returns `NotFound` if no error has been recorded and `BufferTooSmall` if
+/// `length` is less than `farm_last_error_length()`. Neither outcome replaces
+/// the stored message.
+///
+/// # Safety
+///
+/// `buffer` must be null or valid for writes of `length` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn farm_last_error_message(buffer: *mut c_char, length: usize) -> FarmStatus {
+    let copy = || {
+        LAST_ERROR.with(|last| {
+            let last = last.borrow();
+            let message = match last.as_ref() {
+                Some(message) => message.as_bytes(),
+                None => return FarmStatus::NotFound,
+            };
+
+            if buffer.is_null() {
+                return FarmStatus::NullPointer;
+            }
+            if length < message.len() + 1 {
+                return FarmStatus::BufferTooSmall;
+            }
+
+            unsafe {
+                std::ptr::copy_nonoverlapping(message.as_ptr(), buffer as *mut u8, message.len());
+                buffer.add(message.len()).write(0);
+            }
+            FarmStatus::Ok
+        })
+    };
+
+    catch_unwind(AssertUnwindSafe(copy)).unwrap_or(FarmStatus::Panic)
+}
@@ -0,0 +1,1846 @@
+use std::{
+    ffi::{c_char, c_int, c_void, CString},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+};
+
+use crate::*;
+
+const THREADS: usize = 8;
+const ANIMALS_PER_THREAD: usize = 500;
+
+fn functions_map(speak: extern "C" fn(*const c_void, *const c_char)) -> FunctionsMap {
+    FunctionsMap {
+        struct_size: std::mem::size_of::<FunctionsMap>(),
+        version: FUNCTIONS_MAP_VERSION,
+        speak: Some(speak),
+        drop: None,
+        speak_with_reply: None,
+        free_reply: None,
+        serialize: None,
+        deserialize: None,
+    }
+}
+
+extern "C" fn silent_speak(_animal: *const c_void, _message: *const c_char) {}
+
+fn new_farm(functions_map: &FunctionsMap, duplicate_policy: DuplicatePolicy) -> usize {
+    let mut farm_ptr = std::ptr::null();
+    assert_eq!(
+        unsafe { create_farm(functions_map, duplicate_policy as c_int, &mut farm_ptr) },
+        FarmStatus::Ok
+    );
+    farm_ptr as usize
+}
+
+fn animal_count(farm: usize, names: impl Iterator<Item = CString>) -> usize {
+    names
+        .filter(|name| {
+            let mut animal_ptr = std::ptr::null();
+            let status =
+                unsafe { get_animal(farm as *const FarmHandle, name.as_ptr(), &mut animal_ptr) };
+            status == FarmStatus::Ok
+        })
+        .count()
+}
+
+thread_local! {
+    // Pointers handed to `record_release` on this thread, in order.
+    static RELEASED: std::cell::RefCell<Vec<usize>> = const { std::cell::RefCell::new(Vec::new()) };
+}
+
+extern "C" fn record_release(animal: *const c_void) {
+    RELEASED.with(|released| released.borrow_mut().push(animal as usize));
+}
+
+fn take_released() -> Vec<usize> {
+    let mut released = RELEASED.with(|released| released.take());
+    released.sort();
+    released
+}
+
+#[test]
+fn destroy_farm_releases_each_remaining_animal_once() {
+    let farm = new_farm(&functions_map(silent_speak), DuplicatePolicy::Reject) as *const FarmHandle;
+    for (name, animal) in [(c"bessie", 0x10), (c"daisy", 0x20), (c"clover", 0x30)] {
+        let status = unsafe {
+            add_animal(
+                farm,
+                name.as_ptr(),
+                animal as *const c_void,
+                std::ptr::null_mut(),
+            )
+        };
+        assert_eq!(status, FarmStatus::Ok);
+    }
+    // Animals the host took back are no longer the farm's to release.
+    let mut removed = std::ptr::null();
+    assert_eq!(
+        unsafe { remove_animal(farm, c"daisy".as_ptr(), &mut removed) },
+        FarmStatus::Ok
+    );
+    assert_eq!(removed as usize, 0x20);
+
+    assert_eq!(destroy_farm(farm, Some(record_release)), FarmStatus::Ok);
+    assert_eq!(take_released(), [0x10, 0x30]);
+
+    // A second destroy is detected and releases nothing.
+    assert_eq!(
+        destroy_farm(farm, Some(record_release)),
+        FarmStatus::InvalidHandle
+    );
+    assert!(take_released().is_empty());
+    assert_eq!(
+        destroy_farm(std::ptr::null(), None),
+        FarmStatus::NullPointer
+    );
+}
+
+#[test]
+fn exports_report_bad_arguments_with_a_status() {
+    let farm = new_farm(&functions_map(silent_speak), DuplicatePolicy::Reject) as *const FarmHandle;
+    let invalid_utf8 = c"\xffcow".as_ptr();
+    let mut animal = std::ptr::null();
+
+    unsafe {
+        let status = add_animal(
+            farm,
+            std::ptr::null(),
+            std::ptr::dangling(),
+            std::ptr::null_mut(),
+        );
+        assert_eq!(status, FarmStatus::NullPointer);
+        let status = add_animal(
+            farm,
+            invalid_utf8,
+            std::ptr::dangling(),
+            std::ptr::null_mut(),
+        );
+        assert_eq!(status, FarmStatus::InvalidUtf8);
+
+        assert_eq!(
+            get_animal(farm, c"bessie".as_ptr(), &mut animal),
+            FarmStatus::NotFound
+        );
+        assert_eq!(
+            native_speak(farm, c"bessie".as_ptr(), c"moo".as_ptr()),
+            FarmStatus::NotFound
+        );
+
+        let status = add_animal(
+            farm,
+            c"bessie".as_ptr(),
+            std::ptr::dangling(),
+            std::ptr::null_mut(),
+        );
+        assert_eq!(status, FarmStatus::Ok);
+        assert_eq!(
+            get_animal(farm, c"bessie".as_ptr(), std::ptr::null_mut()),
+            FarmStatus::NullPointer
+        );
+        assert_eq!(
+            native_speak(farm, invalid_utf8, c"moo".as_ptr()),
+            FarmStatus::InvalidUtf8
+        );
+        assert_eq!(
+            get_animal(farm, c"bessie".as_ptr(), &mut animal),
+            FarmStatus::Ok
+        );
+        assert_eq!(animal, std::ptr::dangling());
+
+        assert_eq!(
+            create_farm(
+                std::ptr::null(),
+                DuplicatePolicy::Reject as c_int,
+                &mut std::ptr::null()
+            ),
+            FarmStatus::NullPointer
+        );
+        assert_eq!(
+            create_farm(
+                &functions_map(silent_speak),
+                DuplicatePolicy::Reject as c_int,
+                std::ptr::null_mut()
+            ),
+            FarmStatus::NullPointer
+        );
+        assert_eq!(
+            create_farm(&functions_map(silent_speak), 3, &mut std::ptr::null()),
+            FarmStatus::InvalidArgument
+        );
+        assert_eq!(
+            create_farm(&functions_map(silent_speak), -1, &mut std::ptr::null()),
+            FarmStatus::InvalidArgument
+        );
+    }
+
+    assert_eq!(destroy_farm(farm, None), FarmStatus::Ok);
+}
+
+#[test]
+fn panics_are_caught_at_the_boundary() {
+    let status = crate::status::ffi_guard(|| panic!("stampede"));
+    assert_eq!(status, FarmStatus::Panic);
+}
+
+fn last_error() -> String {
+    let length = farm_last_error_length();
+    let mut buffer = vec![0 as c_char; length];
+    let status = unsafe { farm_last_error_message(buffer.as_mut_ptr(), length) };
+    assert_eq!(status, FarmStatus::Ok);
+    unsafe { std::ffi::CStr::from_ptr(buffer.as_ptr()) }
+        .to_str()
+        .unwrap()
+        .to_owned()
+}
+
+#[test]
+fn last_error_describes_the_failed_call() {
+    // A fresh thread has no error recorded yet.
+    thread::spawn(|| {
+        assert_eq!(farm_last_error_length(), 0);
+        let mut buffer = [0 as c_char; 8];
+        let status = unsafe { farm_last_error_message(buffer.as_mut_ptr(), buffer.len()) };
+        assert_eq!(status, FarmStatus::NotFound);
+    })
+    .join()
+    .unwrap();
+
+    let farm = new_farm(&functions_map(silent_speak), DuplicatePolicy::Reject) as *const FarmHandle;
+    let status = unsafe { native_speak(farm, c"bessie".as_ptr(), c"moo".as_ptr()) };
+    assert_eq!(status, FarmStatus::NotFound);
+
+    let message = last_error();
+    assert!(message.contains("bessie"), "{}", message);
+    assert_eq!(farm_last_error_length(), message.len() + 1);
+
+    // Too small a buffer is reported and leaves the message in place.
+    let mut buffer = vec![0 as c_char; message.len()];
+    let status = unsafe { farm_last_error_message(buffer.as_mut_ptr(), buffer.len()) };
+    assert_eq!(status, FarmStatus::BufferTooSmall);
+    assert_eq!(last_error(), message);
+
+    crate::status::ffi_guard(|| panic!("stampede"));
+    assert_eq!(last_error(), "Panicked: stampede");
+
+    assert_eq!(destroy_farm(farm, None), FarmStatus::Ok);
+}
+
+fn animal_named(farm: *const FarmHandle, name: &std::ffi::CStr) -> Result<usize, FarmStatus> {
+    let mut animal = std::ptr::null();
+    match unsafe { get_animal(farm, name.as_ptr(), &mut animal) } {
+        FarmStatus::Ok => Ok(animal as usize),
+        status => Err(status),
+    }
+}
+
+#[test]
+fn remove_returns_the_animal_and_rename_refuses_taken_names() {
+    let farm = new_farm(&functions_map(silent_speak), DuplicatePolicy::Reject) as *const FarmHandle;
+    for (name, animal) in [(c"bessie", 0x10), (c"daisy", 0x20)] {
+        let status = unsafe {
+            add_animal(
+                farm,
+                name.as_ptr(),
+                animal as *const c_void,
+                std::ptr::null_mut(),
+            )
+        };
+        assert_eq!(status, FarmStatus::Ok);
+    }
+
+    unsafe {
+        assert_eq!(
+            rename_animal(farm, c"bessie".as_ptr(), c"daisy".as_ptr()),
+            FarmStatus::AlreadyExists
+        );
+        assert_eq!(animal_named(farm, c"bessie"), Ok(0x10));
+        assert_eq!(animal_named(farm, c"daisy"), Ok(0x20));
+
+        assert_eq!(
+            rename_animal(farm, c"clover".as_ptr(), c"buttercup".as_ptr()),
+            FarmStatus::NotFound
+        );
+        assert_eq!(
+            rename_animal(farm, c"bessie".as_ptr(), c"buttercup".as_ptr()),
+            FarmStatus::Ok
+        );
+        assert_eq!(animal_named(farm, c"bessie"), Err(FarmStatus::NotFound));
+        assert_eq!(animal_named(farm, c"buttercup"), Ok(0x10));
+
+        let mut removed = std::ptr::null();
+        assert_eq!(
+            remove_animal(farm, c"buttercup".as_ptr(), &mut removed),
+            FarmStatus::Ok
+        );
+        assert_eq!(removed as usize, 0x10);
+        assert_eq!(
+            remove_animal(farm, c"buttercup".as_ptr(), &mut removed),
+            FarmStatus::NotFound
+        );
+    }
+
+    assert_eq!(destroy_farm(farm, None), FarmStatus::Ok);
+}
+
+fn add(
+    farm: *const FarmHandle,
+    name: &std::ffi::CStr,
+    animal: usize,
+) -> Result<AddAnimalResult, FarmStatus> {
+    let mut result = unsafe { std::mem::zeroed::<AddAnimalResult>() };
+    match unsafe { add_animal(farm, name.as_ptr(), animal as *const c_void, &mut result) } {
+        FarmStatus::Ok => Ok(result),
+        status => Err(status),
+    }
+}
+
+#[test]
+fn duplicate_names_follow_the_farm_policy() {
+    let reject =
+        new_farm(&functions_map(silent_speak), DuplicatePolicy::Reject) as *const FarmHandle;
+    assert_eq!(
+        add(reject, c"bessie", 0x10).unwrap().outcome,
+        AddOutcome::Added
+    );
+    assert_eq!(
+        add(reject, c"bessie", 0x20).err(),
+        Some(FarmStatus::AlreadyExists)
+    );
+    assert_eq!(animal_named(reject, c"bessie"), Ok(0x10));
+
+    // Without a `drop` slot the replaced animal goes back to the host.
+    let replace =
+        new_farm(&functions_map(silent_speak), DuplicatePolicy::Replace) as *const FarmHandle;
+    add(replace, c"bessie", 0x10).unwrap();
+    let result = add(replace, c"bessie", 0x20).unwrap();
+    assert_eq!(result.outcome, AddOutcome::Replaced);
+    assert_eq!(result.previous_animal as usize, 0x10);
+    assert_eq!(animal_named(replace, c"bessie"), Ok(0x20));
+
+    let keep_both =
+        new_farm(&functions_map(silent_speak), DuplicatePolicy::KeepBoth) as *const FarmHandle;
+    add(keep_both, c"bessie", 0x10).unwrap();
+    let result = add(keep_both, c"bessie", 0x20).unwrap();
+    assert_eq!((result.outcome, result.suffix), (AddOutcome::Suffixed, 2));
+    assert!(result.previous_animal.is_null());
+    assert_eq!(add(keep_both, c"bessie", 0x30).unwrap().suffix, 3);
+    assert_eq!(animal_named(keep_both, c"bessie"), Ok(0x10));
+    assert_eq!(animal_named(keep_both, c"bessie#2"), Ok(0x20));
+    assert_eq!(animal_named(keep_both, c"bessie#3"), Ok(0x30));
+
+    for farm in [reject, replace, keep_both] {
+        assert_eq!(destroy_farm(farm, None), FarmStatus::Ok);
+    }
+}
+
+thread_local! {
+    static SPOKEN_HERE: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+extern "C" fn count_speak_here(_animal: *const c_void, _message: *const c_char) {
+    SPOKEN_HERE.with(|spoken| spoken.set(spoken.get() + 1));
+}
+
+#[test]
+fn older_functions_map_layouts_leave_later_slots_empty() {
+    let speak_only = std::mem::offset_of!(FunctionsMap, drop);
+    // Later slots hold pointers the library must not read, as an older host's
+    // memory past its struct might.
+    let map_of_size = |struct_size| FunctionsMap {
+        struct_size,
+        version: 1,
+        drop: Some(record_release),
+        ..functions_map(count_speak_here)
+    };
+
+    for struct_size in [
+        speak_only,
+        speak_only + 4,
+        speak_only + std::mem::size_of::<SpeakFn>() - 1,
+    ] {
+        let farm =
+            new_farm(&map_of_size(struct_size), DuplicatePolicy::Reject) as *const FarmHandle;
+        unsafe {
+            let status = add_animal(
+                farm,
+                c"bessie".as_ptr(),
+                0x10 as *const c_void,
+                std::ptr::null_mut(),
+            );
+            assert_eq!(status, FarmStatus::Ok);
+            let before = SPOKEN_HERE.get();
+            assert_eq!(
+                native_speak(farm, c"bessie".as_ptr(), c"moo".as_ptr()),
+                FarmStatus::Ok
+            );
+            assert_eq!(SPOKEN_HERE.get(), before + 1);
+
+            let mut removed = std::ptr::null();
+            assert_eq!(
+                remove_animal(farm, c"bessie".as_ptr(), &mut removed),
+                FarmStatus::Ok
+            );
+            assert_eq!(removed as usize, 0x10, "struct_size {}", struct_size);
+        }
+        assert!(take_released().is_empty());
+        assert_eq!(destroy_farm(farm, None), FarmStatus::Ok);
+    }
+
+    // The same map at full size does provide `drop`.
+    let farm = new_farm(
+        &map_of_size(std::mem::size_of::<FunctionsMap>()),
+        DuplicatePolicy::Reject,
+    ) as *const FarmHandle;
+    unsafe {
+        let status = add_animal(
+            farm,
+            c"bessie".as_ptr(),
+            0x10 as *const c_void,
+            std::ptr::null_mut(),
+        );
+        assert_eq!(status, FarmStatus::Ok);
+        let mut removed = std::ptr::null();
+        assert_eq!(
+            remove_animal(farm, c"bessie".as_ptr(), &mut removed),
+            FarmStatus::Ok
+        );
+        assert!(removed.is_null());
+    }
+    assert_eq!(take_released(), [0x10]);
+    assert_eq!(destroy_farm(farm, None), FarmStatus::Ok);
+
+    let mut farm = std::ptr::null();
+    let status =
+        unsafe { create_farm(&map_of_size(4), DuplicatePolicy::Reject as c_int, &mut farm) };
+    assert_eq!(status, FarmStatus::InvalidArgument);
+}
+
+extern "C" fn own_speak(animal: *const c_void, _message: *const c_char) {
+    let (barn, _) = unsafe { barn(animal) };
+    barn.log.lock().unwrap().push("own speak".to_owned());
+}
+
+#[test]
+fn animals_can_dispatch_through_their_own_functions_map() {
+    let barn = Barn::new();
+    let farm = new_farm(&functions_map(log_speak), DuplicatePolicy::Reject) as *const FarmHandle;
+    let own = functions_map(own_speak);
+    let drop_only = FunctionsMap {
+        speak: None,
+        drop: Some(record_release),
+        ..own
+    };
+
+    unsafe {
+        for (name, map) in [
+            (c"bessie", &own as *const FunctionsMap),
+            (c"daisy", &drop_only),
+            (c"clover", std::ptr::null()),
+        ] {
+            let status = add_animal_with_functions(
+                farm,
+                name.as_ptr(),
+                barn.ptr(),
+                map,
+                std::ptr::null_mut(),
+            );
+            assert_eq!(status, FarmStatus::Ok);
+            assert_eq!(
+                native_speak(farm, name.as_ptr(), c"hi".as_ptr()),
+                FarmStatus::Ok
+            );
+        }
+        assert_eq!(barn.log(), ["own speak", "speak", "speak"]);
+
+        // daisy's own `drop` frees her; the others have none.
+        let mut removed = std::ptr::null();
+        assert_eq!(
+            remove_animal(farm, c"daisy".as_ptr(), &mut removed),
+            FarmStatus::Ok
+        );
+        assert!(removed.is_null());
+        assert_eq!(take_released(), [barn.ptr() as usize]);
+        assert_eq!(
+            remove_animal(farm, c"bessie".as_ptr(), &mut removed),
+            FarmStatus::Ok
+        );
+        assert_eq!(removed, barn.ptr());
+    }
+
+    assert_eq!(destroy_farm(farm, None), FarmStatus::Ok);
+}
+
+fn species_info(name: &std::ffi::CStr, functions_map: *const FunctionsMap) -> SpeciesInfo {
+    SpeciesInfo {
+        name: name.as_ptr(),
+        display_name: std::ptr::null(),
+        default_sound: std::ptr::null(),
+        max_count: 0,
+        functions_map,
+    }
+}
+
+#[test]
+fn species_are_registered_listed_and_dispatched_through() {
+    let barn = Barn::new();
+    let farm = new_farm(&functions_map(log_speak), DuplicatePolicy::Reject) as *const FarmHandle;
+    let own = functions_map(own_speak);
+
+    unsafe {
+        let cow_info = SpeciesInfo {
+            display_name: c"Cow".as_ptr(),
+            default_sound: c"moo".as_ptr(),
+            max_count: 1,
+            ..species_info(c"cow", &own)
+        };
+        let mut cow = 0;
+        let mut pig = 0;
+        assert_eq!(register_species(farm, &cow_info, &mut cow), FarmStatus::Ok);
+        let pig_info = species_info(c"pig", std::ptr::null());
+        assert_eq!(register_species(farm, &pig_info, &mut pig), FarmStatus::Ok);
+        assert_eq!(
+            register_species(farm, &pig_info, &mut pig),
+            FarmStatus::AlreadyExists
+        );
+
+        let mut found = u32::MAX;
+        assert_eq!(
+            find_species(farm, c"pig".as_ptr(), &mut found),
+            FarmStatus::Ok
+        );
+        assert_eq!(found, pig);
+        assert_eq!(
+            find_species(farm, c"goat".as_ptr(), &mut found),
+            FarmStatus::NotFound
+        );
+
+        let mut info = std::mem::zeroed::<SpeciesInfo>();
+        assert_eq!(get_species_info(farm, pig, &mut info), FarmStatus::Ok);
+        assert_eq!(std::ffi::CStr::from_ptr(info.display_name), c"pig");
+        assert!(info.default_sound.is_null() && info.functions_map.is_null());
+        assert_eq!(get_species_info(farm, 7, &mut info), FarmStatus::NotFound);
+
+        let mut ids = [u32::MAX; 2];
+        let mut count = 0;
+        let status = list_species(farm, std::ptr::null_mut(), 0, &mut count);
+        assert_eq!((status, count), (FarmStatus::Ok, 2));
+        let status = list_species(farm, ids.as_mut_ptr(), 1, &mut count);
+        assert_eq!((status, count), (FarmStatus::BufferTooSmall, 2));
+        let status = list_species(farm, ids.as_mut_ptr(), 2, &mut count);
+        assert_eq!(status, FarmStatus::Ok);
+        assert_eq!(ids, [cow, pig]);
+
+        for (name, species) in [(c"bessie", cow), (c"wilbur", pig)] {
+            let status = add_animal_of_species(
+                farm,
+                name.as_ptr(),
+                barn.ptr(),
+                species,
+                std::ptr::null_mut(),
+            );
+            assert_eq!(status, FarmStatus::Ok);
+        }
+        let status = add_animal_of_species(
+            farm,
+            c"daisy".as_ptr(),
+            barn.ptr(),
+            cow,
+            std::ptr::null_mut(),
+        );
+        assert_eq!(status, FarmStatus::LimitReached);
+        let status =
+            add_animal_of_species(farm, c"daisy".as_ptr(), barn.ptr(), 7, std::ptr::null_mut());
+        assert_eq!(status, FarmStatus::NotFound);
+
+        let mut cows = 0;
+        assert_eq!(species_animal_count(farm, cow, &mut cows), FarmStatus::Ok);
+        assert_eq!(cows, 1);
+
+        // Cows speak through their species' map, pigs through the farm's;
+        // only cows have a default sound for a null message.
+        assert_eq!(
+            native_speak(farm, c"bessie".as_ptr(), std::ptr::null()),
+            FarmStatus::Ok
+        );
+        assert_eq!(
+            native_speak(farm, c"wilbur".as_ptr(), c"oink".as_ptr()),
+            FarmStatus::Ok
+        );
+        assert_eq!(
+            native_speak(farm, c"wilbur".as_ptr(), std::ptr::null()),
+            FarmStatus::NullPointer
+        );
+        assert_eq!(barn.log(), ["own speak", "speak"]);
+
+        let mut removed = std::ptr::null();
+        assert_eq!(
+            remove_animal(farm, c"bessie".as_ptr(), &mut removed),
+            FarmStatus::Ok
+        );
+        assert_eq!(species_animal_count(farm, cow, &mut cows), FarmStatus::Ok);
+        assert_eq!(cows, 0);
+    }
+
+    assert_eq!(destroy_farm(farm, None), FarmStatus::Ok);
+}
+
+#[test]
+fn drop_slot_frees_animals_the_farm_lets_go_of() {
+    let map = FunctionsMap {
+        drop: Some(record_release),
+        ..functions_map(silent_speak)
+    };
+    let farm = new_farm(&map, DuplicatePolicy::Replace) as *const FarmHandle;
+
+    add(farm, c"bessie", 0x10).unwrap();
+    let mut removed = std::ptr::dangling();
+    assert_eq!(
+        unsafe { remove_animal(farm, c"bessie".as_ptr(), &mut removed) },
+        FarmStatus::Ok
+    );
+    assert!(removed.is_null());
+    assert_eq!(take_released(), [0x10]);
+
+    add(farm, c"daisy", 0x20).unwrap();
+    let result = add(farm, c"daisy", 0x30).unwrap();
+    assert_eq!(result.outcome, AddOutcome::Replaced);
+    assert!(result.previous_animal.is_null());
+    assert_eq!(take_released(), [0x20]);
+
+    // `drop` takes precedence over `release_animal`, once per animal.
+    assert_eq!(destroy_farm(farm, Some(record_release)), FarmStatus::Ok);
+    assert_eq!(take_released(), [0x30]);
+}
+
+thread_local! {
+    static REPLIES_FREED: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+extern "C" fn echo_reply(_animal: *const c_void, message: *const c_char) -> *mut c_char {
+    let message = unsafe { std::ffi::CStr::from_ptr(message) }
+        .to_str()
+        .unwrap();
+    if message == "..." {
+        return std::ptr::null_mut();
+    }
+    CString::new(format!("{}!", message)).unwrap().into_raw()
+}
+
+extern "C" fn free_echo_reply(reply: *mut c_char) {
+    REPLIES_FREED.with(|freed| freed.set(freed.get() + 1));
+    drop(unsafe { CString::from_raw(reply) });
+}
+
+#[test]
+fn speak_with_reply_hands_back_a_copy_of_the_reply() {
+    let map = FunctionsMap {
+        speak_with_reply: Some(echo_reply),
+        free_reply: Some(free_echo_reply),
+        ..functions_map(silent_speak)
+    };
+    let farm = new_farm(&map, DuplicatePolicy::Reject) as *const FarmHandle;
+    add(farm, c"bessie", 0x10).unwrap();
+
+    unsafe {
+        let mut reply = std::ptr::null_mut();
+        assert_eq!(
+            native_speak_with_reply(farm, c"bessie".as_ptr(), c"moo".as_ptr(), &mut reply),
+            FarmStatus::Ok
+        );
+        assert_eq!(std::ffi::CStr::from_ptr(reply), c"moo!");
+        assert_eq!(REPLIES_FREED.get(), 1);
+        farm_free_string(reply);
+
+        assert_eq!(
+            native_speak_with_reply(farm, c"bessie".as_ptr(), c"...".as_ptr(), &mut reply),
+            FarmStatus::Ok
+        );
+        assert!(reply.is_null());
+        assert_eq!(REPLIES_FREED.get(), 1);
+    }
+    assert_eq!(destroy_farm(farm, None), FarmStatus::Ok);
+
+    let farm = new_farm(&functions_map(silent_speak), DuplicatePolicy::Reject) as *const FarmHandle;
+    add(farm, c"bessie", 0x10).unwrap();
+    let mut reply = std::ptr::null_mut();
+    let status =
+        unsafe { native_speak_with_reply(farm, c"bessie".as_ptr(), c"moo".as_ptr(), &mut reply) };
+    assert_eq!(status, FarmStatus::NotSupported);
+    assert_eq!(destroy_farm(farm, None), FarmStatus::Ok);
+}
+
+#[test]
+fn animals_can_be_reached_by_id() {
+    let farm =
+        new_farm(&functions_map(count_speak_here), DuplicatePolicy::Reject) as *const FarmHandle;
+    let bessie = add(farm, c"bessie", 0x10).unwrap().id;
+    let daisy = add(farm, c"daisy", 0x20).unwrap().id;
+    assert!(bessie != 0 && daisy != 0 && bessie != daisy);
+
+    unsafe {
+        let mut id = 0;
+        assert_eq!(
+            get_animal_id(farm, c"daisy".as_ptr(), &mut id),
+            FarmStatus::Ok
+        );
+        assert_eq!(id, daisy);
+
+        // Ids survive a rename.
+        assert_eq!(
+            rename_animal(farm, c"bessie".as_ptr(), c"buttercup".as_ptr()),
+            FarmStatus::Ok
+        );
+        let mut animal = std::ptr::null();
+        assert_eq!(get_animal_by_id(farm, bessie, &mut animal), FarmStatus::Ok);
+        assert_eq!(animal as usize, 0x10);
+
+        let before = SPOKEN_HERE.get();
+        assert_eq!(
+            native_speak_by_id(farm, bessie, c"moo".as_ptr()),
+            FarmStatus::Ok
+        );
+        assert_eq!(SPOKEN_HERE.get(), before + 1);
+
+        assert_eq!(
+            remove_animal_by_id(farm, daisy, &mut animal),
+            FarmStatus::Ok
+        );
+        assert_eq!(animal as usize, 0x20);
+        assert_eq!(
+            get_animal_id(farm, c"daisy".as_ptr(), &mut id),
+            FarmStatus::NotFound
+        );
+        assert_eq!(get_animal_by_id(farm, 0, &mut animal), FarmStatus::NotFound);
+        assert_eq!(
+            native_speak_by_id(farm, 12345, c"moo".as_ptr()),
+            FarmStatus::NotFound
+        );
+    }
+
+    assert_eq!(destroy_farm(farm, None), FarmStatus::Ok);
+}
+
+#[test]
+fn stale_ids_are_rejected_after_their_slot_is_reused() {
+    let farm =
+        new_farm(&functions_map(count_speak_here), DuplicatePolicy::Reject) as *const FarmHandle;
+    let bessie = add(farm, c"bessie", 0x10).unwrap().id;
+    let mut animal = std::ptr::null();
+    assert_eq!(
+        unsafe { remove_animal_by_id(farm, bessie, &mut animal) },
+        FarmStatus::Ok
+    );
+
+    // daisy moves into the slot bessie left.
+    let daisy = add(farm, c"daisy", 0x20).unwrap().id;
+    assert_eq!(daisy as u32, bessie as u32);
+    assert_ne!(daisy, bessie);
+
+    unsafe {
+        assert_eq!(
+            get_animal_by_id(farm, bessie, &mut animal),
+            FarmStatus::NotFound
+        );
+        let before = SPOKEN_HERE.get();
+        assert_eq!(
+            native_speak_by_id(farm, bessie, c"moo".as_ptr()),
+            FarmStatus::NotFound
+        );
+        assert_eq!(SPOKEN_HERE.get(), before);
+        assert_eq!(
+            remove_animal_by_id(farm, bessie, &mut animal),
+            FarmStatus::NotFound
+        );
+        assert_eq!(get_animal_by_id(farm, daisy, &mut animal), FarmStatus::Ok);
+        assert_eq!(animal as usize, 0x20);
+    }
+
+    assert_eq!(destroy_farm(farm, None), FarmStatus::Ok);
+}
+
+static SPOKEN: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn count_speak(_animal: *const c_void, _message: *const c_char) {
+    SPOKEN.fetch_add(1, Ordering::SeqCst);
+}
+
+#[test]
+fn concurrent_add_get_speak_on_distinct_names() {
+    let farm = new_farm(&functions_map(count_speak), DuplicatePolicy::Reject);
+
+    let workers: Vec<_> = (0..THREADS)
+        .map(|thread| {
+            thread::spawn(move || {
+                let farm_ptr = farm as *const FarmHandle;
+                for n in 0..ANIMALS_PER_THREAD {
+                    let name = CString::new(format!("animal-{}-{}", thread, n)).unwrap();
+                    let animal = (thread * ANIMALS_PER_THREAD + n + 1) as *const c_void;
+
+                    unsafe {
+                        assert_eq!(
+                            add_animal(farm_ptr, name.as_ptr(), animal, std::ptr::null_mut()),
+                            FarmStatus::Ok
+                        );
+
+                        let mut found = std::ptr::null();
+                        assert_eq!(
+                            get_animal(farm_ptr, name.as_ptr(), &mut found),
+                            FarmStatus::Ok
+                        );
+                        assert_eq!(found, animal);
+
+                        assert_eq!(
+                            native_speak(farm_ptr, name.as_ptr(), c"hello".as_ptr()),
+                            FarmStatus::Ok
+                        );
+                    }
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+
+    assert_eq!(SPOKEN.load(Ordering::SeqCst), THREADS * ANIMALS_PER_THREAD);
+    let names = (0..THREADS).flat_map(|thread| {
+        (0..ANIMALS_PER_THREAD)
+            .map(move |n| CString::new(format!("animal-{}-{}", thread, n)).unwrap())
+    });
+    assert_eq!(animal_count(farm, names), THREADS * ANIMALS_PER_THREAD);
+
+    assert_eq!(
+        destroy_farm(farm as *const FarmHandle, None),
+        FarmStatus::Ok
+    );
+}
+
+#[test]
+fn concurrent_add_remove_on_shared_names() {
+    let farm = new_farm(&functions_map(silent_speak), DuplicatePolicy::Replace);
+    let names = || (0..32).map(|n| CString::new(format!("shared-{}", n)).unwrap());
+
+    let workers: Vec<_> = (0..THREADS)
+        .map(|thread| {
+            thread::spawn(move || {
+                let farm_ptr = farm as *const FarmHandle;
+                let animal = (thread + 1) as *const c_void;
+                for _ in 0..ANIMALS_PER_THREAD / 10 {
+                    for name in names() {
+                        let mut removed = std::ptr::null();
+
+                        unsafe {
+                            assert_eq!(
+                                add_animal(farm_ptr, name.as_ptr(), animal, std::ptr::null_mut()),
+                                FarmStatus::Ok
+                            );
+                            native_speak(farm_ptr, name.as_ptr(), c"hi".as_ptr());
+                            remove_animal(farm_ptr, name.as_ptr(), &mut removed);
+                        }
+                    }
+                }
+                for name in names() {
+                    unsafe { add_animal(farm_ptr, name.as_ptr(), animal, std::ptr::null_mut()) };
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+
+    // Each thread's last touch of every name is an add, so whichever thread
+    // got there last, all of them must be present.
+    assert_eq!(animal_count(farm, names()), 32);
+
+    assert_eq!(
+        destroy_farm(farm as *const FarmHandle, None),
+        FarmStatus::Ok
+    );
+}
+
+// Shared by every animal in a reentrancy test: the animal pointer handed to
+// callbacks is the barn, which knows its farm and logs what happened.
+struct Barn {
+    farm: AtomicUsize,
+    log: Mutex<Vec<String>>,
+}
+
+impl Barn {
+    fn new() -> Barn {
+        Barn {
+            farm: AtomicUsize::new(0),
+            log: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn ptr(&self) -> *const c_void {
+        self as *const Barn as *const c_void
+    }
+
+    fn log(&self) -> Vec<String> {
+        self.log.lock().unwrap().clone()
+    }
+}
+
+unsafe fn barn<'a>(animal: *const c_void) -> (&'a Barn, *const FarmHandle) {
+    let barn = unsafe { &*(animal as *const Barn) };
+    (barn, barn.farm.load(Ordering::SeqCst) as *const FarmHandle)
+}
+
+extern "C" fn reentrant_speak(animal: *const c_void, message: *const c_char) {
+    let (barn, farm) = unsafe { barn(animal) };
+    let message = unsafe { std::ffi::CStr::from_ptr(message) }
+        .to_str()
+        .unwrap();
+    barn.log.lock().unwrap().push(format!("speak {}", message));
+
+    unsafe {
+        match message {
+            "add a calf" => {
+                let status = add_animal(farm, c"calf".as_ptr(), animal, std::ptr::null_mut());
+                assert_eq!(status, FarmStatus::Ok);
+                let status = native_speak(farm, c"calf".as_ptr(), c"moo".as_ptr());
+                assert_eq!(status, FarmStatus::Ok);
+            }
+            "leave" => {
+                let mut removed = std::ptr::null();
+                let status = remove_animal(farm, c"bessie".as_ptr(), &mut removed);
+                assert_eq!(status, FarmStatus::Ok);
+            }
+            _ => {}
+        }
+    }
+}
+
+extern "C" fn reentrant_drop(animal: *const c_void) {
+    let (barn, farm) = unsafe { barn(animal) };
+
+    let mut found = std::ptr::null();
+    let status = unsafe { get_animal(farm, c"bessie".as_ptr(), &mut found) };
+    barn.log
+        .lock()
+        .unwrap()
+        .push(format!("drop sees bessie: {:?}", status));
+}
+
+fn reentrant_farm(barn: &Barn, duplicate_policy: DuplicatePolicy) -> *const FarmHandle {
+    let functions_map = FunctionsMap {
+        drop: Some(reentrant_drop),
+        ..functions_map(reentrant_speak)
+    };
+    let farm = new_farm(&functions_map, duplicate_policy);
+    barn.farm.store(farm, Ordering::SeqCst);
+
+    let status = unsafe {
+        add_animal(
+            farm as *const FarmHandle,
+            c"bessie".as_ptr(),
+            barn.ptr(),
+            std::ptr::null_mut(),
+        )
+    };
+    assert_eq!(status, FarmStatus::Ok);
+    farm as *const FarmHandle
+}
+
+#[test]
+fn speak_callback_can_add_animals_and_make_them_speak() {
+    let barn = Barn::new();
+    let farm = reentrant_farm(&barn, DuplicatePolicy::Reject);
+
+    let status = unsafe { native_speak(farm, c"bessie".as_ptr(), c"add a calf".as_ptr()) };
+    assert_eq!(status, FarmStatus::Ok);
+    assert_eq!(barn.log(), ["speak add a calf", "speak moo"]);
+
+    let mut calf = std::ptr::null();
+    assert_eq!(
+        unsafe { get_animal(farm, c"calf".as_ptr(), &mut calf) },
+        FarmStatus::Ok
+    );
+    assert_eq!(calf, barn.ptr());
+
+    assert_eq!(destroy_farm(farm, None), FarmStatus::Ok);
+}
+
+#[test]
+fn speak_callback_can_remove_the_speaking_animal() {
+    let barn = Barn::new();
+    let farm = reentrant_farm(&barn, DuplicatePolicy::Reject);
+
+    let status = unsafe { native_speak(farm, c"bessie".as_ptr(), c"leave".as_ptr()) };
+    assert_eq!(status, FarmStatus::Ok);
+    assert_eq!(barn.log(), ["speak leave", "drop sees bessie: NotFound"]);
+
+    assert_eq!(destroy_farm(farm, None), FarmStatus::Ok);
+}
+
+#[test]
+fn drop_callback_of_replaced_animal_can_use_the_farm() {
+    let barn = Barn::new();
+    let farm = reentrant_farm(&barn, DuplicatePolicy::Replace);
+
+    let mut result = unsafe { std::mem::zeroed::<AddAnimalResult>() };
+    let status = unsafe { add_animal(farm, c"bessie".as_ptr(), barn.ptr(), &mut result) };
+    assert_eq!(status, FarmStatus::Ok);
+    assert_eq!(result.outcome, AddOutcome::Replaced);
+    assert!(result.previous_animal.is_null());
+    assert_eq!(barn.log(), ["drop sees bessie: Ok"]);
+
+    assert_eq!(destroy_farm(farm, None), FarmStatus::Ok);
+}
+
+#[test]
+fn callbacks_during_destroy_see_an_invalid_handle() {
+    let barn = Barn::new();
+    let farm = reentrant_farm(&barn, DuplicatePolicy::Reject);
+
+    assert_eq!(destroy_farm(farm, None), FarmStatus::Ok);
+    assert_eq!(barn.log(), ["drop sees bessie: InvalidHandle"]);
+}
+
+#[test]
+fn calls_that_lose_the_race_with_destroy_farm_see_an_invalid_handle() {
+    let farm = new_farm(&functions_map(silent_speak), DuplicatePolicy::Reject) as *const FarmHandle;
+    add(farm, c"bessie", 1).unwrap();
+    // What a call holds once it has looked the farm up, just before
+    // `destroy_farm` empties it on another thread.
+    let shared = crate::handle::farm_arg(farm).unwrap();
+    assert_eq!(destroy_farm(farm, None), FarmStatus::Ok);
+
+    let late = Farm::from_shared(shared.clone());
+    let statuses = [
+        late.animal_ptr("bessie").map(|_| ()),
+        late.speak_raw("bessie", c"moo".as_ptr()),
+        late.rename_animal("bessie", "daisy"),
+        late.take_animal("bessie").map(|_| ()),
+        late.add_animal("daisy", Quiet).map(|_| ()),
+        shared.write().map(|_| ()),
+    ];
+    for status in statuses {
+        assert_eq!(status.unwrap_err().status(), FarmStatus::InvalidHandle);
+    }
+    assert_eq!(late.len(), 0);
+}
+
+struct Quiet;
+
+impl Animal for Quiet {
+    fn speak(&self, _message: &str) {}
+}
+
+#[test]
+fn iteration_skips_removed_animals_and_ignores_added_ones() {
+    let farm = new_farm(&functions_map(silent_speak), DuplicatePolicy::Reject) as *const FarmHandle;
+    for name in [c"bessie", c"daisy", c"clover"] {
+        let status = unsafe {
+            add_animal(
+                farm,
+                name.as_ptr(),
+                std::ptr::dangling(),
+                std::ptr::null_mut(),
+            )
+        };
+        assert_eq!(status, FarmStatus::Ok);
+    }
+
+    let mut count = 0;
+    assert_eq!(
+        unsafe { farm_animal_count(farm, &mut count) },
+        FarmStatus::Ok
+    );
+    assert_eq!(count, 3);
+
+    let mut iter = std::ptr::null_mut();
+    assert_eq!(unsafe { farm_iter_begin(farm, &mut iter) }, FarmStatus::Ok);
+    unsafe {
+        let mut removed = std::ptr::null();
+        assert_eq!(
+            remove_animal(farm, c"daisy".as_ptr(), &mut removed),
+            FarmStatus::Ok
+        );
+        let status = add_animal(
+            farm,
+            c"calf".as_ptr(),
+            std::ptr::dangling(),
+            std::ptr::null_mut(),
+        );
+        assert_eq!(status, FarmStatus::Ok);
+        assert_eq!(
+            rename_animal(farm, c"clover".as_ptr(), c"clove".as_ptr()),
+            FarmStatus::Ok
+        );
+    }
+
+    let mut visited = Vec::new();
+    loop {
+        let mut name = std::ptr::null();
+        let status =
+            unsafe { farm_iter_next(iter, std::ptr::null_mut(), &mut name, std::ptr::null_mut()) };
+        if status == FarmStatus::Exhausted {
+            break;
+        }
+        assert_eq!(status, FarmStatus::Ok);
+        visited.push(
+            unsafe { std::ffi::CStr::from_ptr(name) }
+                .to_str()
+                .unwrap()
+                .to_owned(),
+        );
+    }
+    unsafe { farm_iter_end(iter) };
+    assert_eq!(visited, ["bessie", "clove"]);
+
+    assert_eq!(destroy_farm(farm, None), FarmStatus::Ok);
+}
+
+extern "C" fn remove_while_visiting(
+    user_data: *mut c_void,
+    _id: AnimalId,
+    name: *const c_char,
+    _animal: *const c_void,
+) -> bool {
+    let farm = user_data as *const FarmHandle;
+    let mut removed = std::ptr::null();
+    assert_eq!(
+        unsafe { remove_animal(farm, name, &mut removed) },
+        FarmStatus::Ok
+    );
+    true
+}
+
+#[test]
+fn for_each_callback_can_remove_every_animal() {
+    let farm =
+        new_farm(&functions_map(silent_speak), DuplicatePolicy::KeepBoth) as *const FarmHandle;
+    for _ in 0..4 {
+        let status = unsafe {
+            add_animal(
+                farm,
+                c"bessie".as_ptr(),
+                std::ptr::dangling(),
+                std::ptr::null_mut(),
+            )
+        };
+        assert_eq!(status, FarmStatus::Ok);
+    }
+
+    let status = farm_for_each_animal(farm, Some(remove_while_visiting), farm as *mut c_void);
+    assert_eq!(status, FarmStatus::Ok);
+
+    let mut count = usize::MAX;
+    assert_eq!(
+        unsafe { farm_animal_count(farm, &mut count) },
+        FarmStatus::Ok
+    );
+    assert_eq!(count, 0);
+
+    assert_eq!(destroy_farm(farm, None), FarmStatus::Ok);
+}
+
+extern "C" fn log_speak(animal: *const c_void, _message: *const c_char) {
+    let (barn, _) = unsafe { barn(animal) };
+    barn.log.lock().unwrap().push("speak".to_owned());
+}
+
+extern "C" fn select_even_ids(
+    _user_data: *mut c_void,
+    id: AnimalId,
+    _name: *const c_char,
+    _animal: *const c_void,
+) -> bool {
+    id & 1 == 0
+}
+
+#[test]
+fn broadcast_speaks_to_every_selected_animal() {
+    let barn = Barn::new();
+    let farm = new_farm(&functions_map(log_speak), DuplicatePolicy::Reject) as *const FarmHandle;
+    let mut ids = Vec::new();
+    for name in [c"cow-1", c"cow-2", c"cow-10", c"pig-1"] {
+        let mut result = unsafe { std::mem::zeroed::<AddAnimalResult>() };
+        let status = unsafe { add_animal(farm, name.as_ptr(), barn.ptr(), &mut result) };
+        assert_eq!(status, FarmStatus::Ok);
+        ids.push(result.id);
+    }
+
+    let mut spoken = 0;
+    let status = unsafe { native_speak_all(farm, c"hi".as_ptr(), &mut spoken) };
+    assert_eq!((status, spoken), (FarmStatus::Ok, 4));
+
+    for (pattern, expected) in [
+        ("cow-?", 2),
+        ("cow-*", 3),
+        ("*-1*", 3),
+        ("*", 4),
+        ("cow", 0),
+    ] {
+        let pattern = CString::new(pattern).unwrap();
+        let status =
+            unsafe { native_speak_matching(farm, c"hi".as_ptr(), pattern.as_ptr(), &mut spoken) };
+        assert_eq!(
+            (status, spoken),
+            (FarmStatus::Ok, expected),
+            "{:?}",
+            pattern
+        );
+    }
+
+    let even = ids.iter().filter(|&&id| id & 1 == 0).count();
+    let status = unsafe {
+        native_speak_where(
+            farm,
+            c"hi".as_ptr(),
+            Some(select_even_ids),
+            std::ptr::null_mut(),
+            &mut spoken,
+        )
+    };
+    assert_eq!((status, spoken), (FarmStatus::Ok, even));
+
+    assert_eq!(barn.log().len(), 4 + 2 + 3 + 3 + 4 + even);
+    assert_eq!(destroy_farm(farm, None), FarmStatus::Ok);
+}
+
+extern "C" fn remove_daisy_speak(animal: *const c_void, _message: *const c_char) {
+    let (barn, farm) = unsafe { barn(animal) };
+    barn.log.lock().unwrap().push("speak".to_owned());
+
+    let mut removed = std::ptr::null();
+    unsafe { remove_animal(farm, c"daisy".as_ptr(), &mut removed) };
+}
+
+#[test]
+fn broadcast_skips_animals_removed_by_an_earlier_speaker() {
+    let barn = Barn::new();
+    let farm = new_farm(&functions_map(remove_daisy_speak), DuplicatePolicy::Reject);
+    barn.farm.store(farm, Ordering::SeqCst);
+    let farm = farm as *const FarmHandle;
+    for name in [c"bessie", c"daisy", c"clover"] {
+        let status = unsafe { add_animal(farm, name.as_ptr(), barn.ptr(), std::ptr::null_mut()) };
+        assert_eq!(status, FarmStatus::Ok);
+    }
+
+    // bessie speaks first and removes daisy before her turn comes.
+    let mut spoken = 0;
+    let status = unsafe { native_speak_all(farm, c"hi".as_ptr(), &mut spoken) };
+    assert_eq!((status, spoken), (FarmStatus::Ok, 2));
+    assert_eq!(barn.log().len(), 2);
+
+    assert_eq!(destroy_farm(farm, None), FarmStatus::Ok);
+}
+
+#[test]
+fn tags_and_attributes_are_stored_per_animal() {
+    let farm = new_farm(&functions_map(silent_speak), DuplicatePolicy::Reject) as *const FarmHandle;
+    let mut ids = Vec::new();
+    for name in [c"bessie", c"daisy", c"clover"] {
+        let mut result = unsafe { std::mem::zeroed::<AddAnimalResult>() };
+        let status = unsafe { add_animal(farm, name.as_ptr(), std::ptr::dangling(), &mut result) };
+        assert_eq!(status, FarmStatus::Ok);
+        ids.push(result.id);
+    }
+
+    unsafe {
+        for name in [c"bessie", c"clover"] {
+            assert_eq!(
+                add_animal_tag(farm, name.as_ptr(), c"barn-2".as_ptr()),
+                FarmStatus::Ok
+            );
+        }
+        assert_eq!(
+            add_animal_tag(farm, c"daisy".as_ptr(), c"".as_ptr()),
+            FarmStatus::InvalidArgument
+        );
+
+        let mut tagged = [0; 3];
+        let mut count = 0;
+        let status =
+            farm_animals_with_tag(farm, c"barn-2".as_ptr(), tagged.as_mut_ptr(), 1, &mut count);
+        assert_eq!((status, count), (FarmStatus::BufferTooSmall, 2));
+        let status =
+            farm_animals_with_tag(farm, c"barn-2".as_ptr(), tagged.as_mut_ptr(), 3, &mut count);
+        assert_eq!(status, FarmStatus::Ok);
+        assert_eq!(tagged[..count], [ids[0], ids[2]]);
+
+        assert_eq!(
+            remove_animal_tag(farm, c"clover".as_ptr(), c"barn-2".as_ptr()),
+            FarmStatus::Ok
+        );
+        assert_eq!(
+            remove_animal_tag(farm, c"clover".as_ptr(), c"barn-2".as_ptr()),
+            FarmStatus::NotFound
+        );
+        let mut has_tag = true;
+        assert_eq!(
+            animal_has_tag(farm, c"clover".as_ptr(), c"barn-2".as_ptr(), &mut has_tag),
+            FarmStatus::Ok
+        );
+        assert!(!has_tag);
+
+        let bessie = c"bessie".as_ptr();
+        assert_eq!(
+            set_animal_attribute_int(farm, bessie, c"age".as_ptr(), 4),
+            FarmStatus::Ok
+        );
+        assert_eq!(
+            set_animal_attribute_float(farm, bessie, c"weight".as_ptr(), 612.5),
+            FarmStatus::Ok
+        );
+        assert_eq!(
+            set_animal_attribute_bool(farm, bessie, c"milked".as_ptr(), true),
+            FarmStatus::Ok
+        );
+        assert_eq!(
+            set_animal_attribute_string(farm, bessie, c"breed".as_ptr(), c"jersey".as_ptr()),
+            FarmStatus::Ok
+        );
+
+        let mut age = 0;
+        assert_eq!(
+            get_animal_attribute_int(farm, bessie, c"age".as_ptr(), &mut age),
+            FarmStatus::Ok
+        );
+        assert_eq!(age, 4);
+        let mut weight = 0.0;
+        assert_eq!(
+            get_animal_attribute_float(farm, bessie, c"weight".as_ptr(), &mut weight),
+            FarmStatus::Ok
+        );
+        assert_eq!(weight, 612.5);
+        let mut milked = false;
+        assert_eq!(
+            get_animal_attribute_bool(farm, bessie, c"milked".as_ptr(), &mut milked),
+            FarmStatus::Ok
+        );
+        assert!(milked);
+        let mut breed = std::ptr::null_mut();
+        assert_eq!(
+            get_animal_attribute_string(farm, bessie, c"breed".as_ptr(), &mut breed),
+            FarmStatus::Ok
+        );
+        assert_eq!(std::ffi::CStr::from_ptr(breed), c"jersey");
+        farm_free_string(breed);
+
+        assert_eq!(
+            get_animal_attribute_float(farm, bessie, c"age".as_ptr(), &mut weight),
+            FarmStatus::InvalidArgument
+        );
+        assert_eq!(
+            set_animal_attribute_float(farm, bessie, c"age".as_ptr(), 4.5),
+            FarmStatus::Ok
+        );
+        let mut kind = AttributeKind::Int;
+        assert_eq!(
+            get_animal_attribute_kind(farm, bessie, c"age".as_ptr(), &mut kind),
+            FarmStatus::Ok
+        );
+        assert_eq!(kind, AttributeKind::Float);
+
+        assert_eq!(
+            remove_animal_attribute(farm, bessie, c"age".as_ptr()),
+            FarmStatus::Ok
+        );
+        assert_eq!(
+            get_animal_attribute_kind(farm, bessie, c"age".as_ptr(), &mut kind),
+            FarmStatus::NotFound
+        );
+        assert_eq!(
+            get_animal_attribute_int(farm, c"daisy".as_ptr(), c"age".as_ptr(), &mut age),
+            FarmStatus::NotFound
+        );
+    }
+
+    assert_eq!(destroy_farm(farm, None), FarmStatus::Ok);
+}
+
+fn query(farm: *const FarmHandle, query: &str) -> Result<Vec<AnimalId>, FarmStatus> {
+    let query = CString::new(query).unwrap();
+    let mut animal_ids = [0; 16];
+    let mut count = 0;
+    let status = unsafe {
+        farm_query(
+            farm,
+            query.as_ptr(),
+            animal_ids.as_mut_ptr(),
+            animal_ids.len(),
+            &mut count,
+        )
+    };
+    match status {
+        FarmStatus::Ok => Ok(animal_ids[..count].to_vec()),
+        status => Err(status),
+    }
+}
+
+#[test]
+fn queries_select_animals_by_species_tags_and_attributes() {
+    let farm = new_farm(&functions_map(silent_speak), DuplicatePolicy::Reject) as *const FarmHandle;
+    let mut species_ids = Vec::new();
+    for name in [c"cow", c"pig"] {
+        let info = SpeciesInfo {
+            name: name.as_ptr(),
+            display_name: std::ptr::null(),
+            default_sound: std::ptr::null(),
+            max_count: 0,
+            functions_map: std::ptr::null(),
+        };
+        let mut species_id = 0;
+        assert_eq!(
+            unsafe { register_species(farm, &info, &mut species_id) },
+            FarmStatus::Ok
+        );
+        species_ids.push(species_id);
+    }
+
+    // name, species, age, tag
+    let animals = [
+        (c"bessie", 0, 5, Some(c"barn-2")),
+        (c"daisy", 0, 2, Some(c"barn-2")),
+        (c"clover", 0, 7, None),
+        (c"wilbur", 1, 4, Some(c"barn-2")),
+    ];
+    let mut ids = Vec::new();
+    for (name, species, age, tag) in animals {
+        let mut result = unsafe { std::mem::zeroed::<AddAnimalResult>() };
+        unsafe {
+            let status = add_animal_of_species(
+                farm,
+                name.as_ptr(),
+                std::ptr::dangling(),
+                species_ids[species],
+                &mut result,
+            );
+            assert_eq!(status, FarmStatus::Ok);
+            let status = set_animal_attribute_int(farm, name.as_ptr(), c"age".as_ptr(), age);
+            assert_eq!(status, FarmStatus::Ok);
+            if let Some(tag) = tag {
+                assert_eq!(
+                    add_animal_tag(farm, name.as_ptr(), tag.as_ptr()),
+                    FarmStatus::Ok
+                );
+            }
+        }
+        ids.push(result.id);
+    }
+    let [bessie, daisy, clover, wilbur] = ids[..] else {
+        unreachable!()
+    };
+
+    assert_eq!(
+        query(farm, r#"species == "cow" && age > 3 && tag:barn-2"#),
+        Ok(vec![bessie])
+    );
+    assert_eq!(
+        query(farm, "age >= 4.5 || name ~ \"d*\""),
+        Ok(vec![bessie, daisy, clover])
+    );
+    assert_eq!(
+        query(farm, "!(tag:barn-2) || species != \"cow\""),
+        Ok(vec![clover, wilbur])
+    );
+    assert_eq!(query(farm, "weight < 100"), Ok(vec![]));
+    assert_eq!(query(farm, "age == \"5\""), Ok(vec![]));
+
+    for malformed in [
+        "",
+        "age >",
+        "age 3",
+        "(tag:barn-2",
+        "tag:",
+        "name ~ 3",
+        "age > 3 &&",
+        "\"open",
+    ] {
+        assert_eq!(
+            query(farm, malformed),
+            Err(FarmStatus::InvalidArgument),
+            "{}",
+            malformed
+        );
+    }
+
+    assert_eq!(destroy_farm(farm, None), FarmStatus::Ok);
+}
+
+#[test]
+fn deeply_nested_queries_are_rejected_without_overflowing() {
+    let farm = new_farm(&functions_map(silent_speak), DuplicatePolicy::Reject) as *const FarmHandle;
+    let bessie = add(farm, c"bessie", 0x10).unwrap().id;
+    assert_eq!(
+        unsafe { add_animal_tag(farm, c"bessie".as_ptr(), c"x".as_ptr()) },
+        FarmStatus::Ok
+    );
+
+    let nots = |depth| format!("{}tag:x", "!".repeat(depth));
+    let parens = |depth| format!("{}tag:x{}", "(".repeat(depth), ")".repeat(depth));
+    assert_eq!(query(farm, &nots(256)), Ok(vec![bessie]));
+    assert_eq!(query(farm, &parens(256)), Ok(vec![bessie]));
+    assert_eq!(query(farm, &nots(257)), Err(FarmStatus::InvalidArgument));
+    assert_eq!(query(farm, &parens(257)), Err(FarmStatus::InvalidArgument));
+    assert_eq!(
+        query(farm, &nots(1_000_000)),
+        Err(FarmStatus::InvalidArgument)
+    );
+
+    // Long runs of `&&` and `||` are not nesting.
+    let chain = vec!["tag:x"; 100_000].join(" && ");
+    assert_eq!(query(farm, &chain), Ok(vec![bessie]));
+    let chain = vec!["tag:y"; 100_000].join(" || ");
+    assert_eq!(query(farm, &chain), Ok(vec![]));
+
+    assert_eq!(destroy_farm(farm, None), FarmStatus::Ok);
+}
+
+// Snapshot test animals are boxed C strings holding their sound.
+fn sound_animal(sound: &std::ffi::CStr) -> *const c_void {
+    Box::into_raw(Box::new(sound.to_owned())) as *const c_void
+}
+
+extern "C" fn serialize_sound(animal: *const c_void) -> *mut c_char {
+    let sound = unsafe { &*(animal as *const CString) };
+    sound.clone().into_raw()
+}
+
+extern "C" fn free_state(state: *mut c_char) {
+    drop(unsafe { CString::from_raw(state) });
+}
+
+extern "C" fn deserialize_sound(_name: *const c_char, state: *const c_char) -> *const c_void {
+    sound_animal(unsafe { std::ffi::CStr::from_ptr(state) })
+}
+
+extern "C" fn drop_sound(animal: *const c_void) {
+    drop(unsafe { Box::from_raw(animal as *mut CString) });
+}
+
+fn sound_functions_map() -> FunctionsMap {
+    FunctionsMap {
+        drop: Some(drop_sound),
+        free_reply: Some(free_state),
+        serialize: Some(serialize_sound),
+        deserialize: Some(deserialize_sound),
+        ..functions_map(silent_speak)
+    }
+}
+
+// A farm with a species, tags, every kind of attribute and host state, for
+// snapshot tests.
+fn snapshot_farm() -> *const FarmHandle {
+    let farm = new_farm(&sound_functions_map(), DuplicatePolicy::Reject) as *const FarmHandle;
+    let info = SpeciesInfo {
+        name: c"cow".as_ptr(),
+        display_name: c"Cow".as_ptr(),
+        default_sound: c"moo".as_ptr(),
+        max_count: 10,
+        functions_map: std::ptr::null(),
+    };
+    let mut cow = 0;
+    unsafe {
+        assert_eq!(register_species(farm, &info, &mut cow), FarmStatus::Ok);
+        let status = add_animal_of_species(
+            farm,
+            c"bessie".as_ptr(),
+            sound_animal(c"moo!"),
+            cow,
+            std::ptr::null_mut(),
+        );
+        assert_eq!(status, FarmStatus::Ok);
+        let status = add_animal(
+            farm,
+            c"rex".as_ptr(),
+            sound_animal(c"woof"),
+            std::ptr::null_mut(),
+        );
+        assert_eq!(status, FarmStatus::Ok);
+        assert_eq!(
+            add_animal_tag(farm, c"bessie".as_ptr(), c"barn-2".as_ptr()),
+            FarmStatus::Ok
+        );
+        let rex = c"rex".as_ptr();
+        let status = set_animal_attribute_float(farm, rex, c"weight".as_ptr(), 31.5);
+        assert_eq!(status, FarmStatus::Ok);
+        let status = set_animal_attribute_int(farm, rex, c"age".as_ptr(), -3);
+        assert_eq!(status, FarmStatus::Ok);
+        let status = set_animal_attribute_string(farm, rex, c"owner".as_ptr(), c"ana".as_ptr());
+        assert_eq!(status, FarmStatus::Ok);
+        let status = set_animal_attribute_bool(farm, rex, c"good".as_ptr(), true);
+        assert_eq!(status, FarmStatus::Ok);
+    }
+    farm
+}
+
+#[test]
+fn json_snapshot_round_trips_metadata_and_host_state() {
+    let farm = snapshot_farm();
+    let mut json = std::ptr::null_mut();
+    unsafe {
+        assert_eq!(farm_save_json(farm, &mut json), FarmStatus::Ok);
+    }
+    assert_eq!(destroy_farm(farm, None), FarmStatus::Ok);
+
+    let restored = new_farm(&sound_functions_map(), DuplicatePolicy::Reject) as *const FarmHandle;
+    unsafe {
+        let mut count = 0;
+        assert_eq!(farm_load_json(restored, json, &mut count), FarmStatus::Ok);
+        assert_eq!(count, 2);
+        farm_free_string(json);
+
+        let mut bessie = std::ptr::null();
+        assert_eq!(
+            get_animal(restored, c"bessie".as_ptr(), &mut bessie),
+            FarmStatus::Ok
+        );
+        assert_eq!(&*(bessie as *const CString), c"moo!");
+        let mut has_tag = false;
+        let status = animal_has_tag(
+            restored,
+            c"bessie".as_ptr(),
+            c"barn-2".as_ptr(),
+            &mut has_tag,
+        );
+        assert_eq!((status, has_tag), (FarmStatus::Ok, true));
+
+        let mut weight = 0.0;
+        let status =
+            get_animal_attribute_float(restored, c"rex".as_ptr(), c"weight".as_ptr(), &mut weight);
+        assert_eq!((status, weight), (FarmStatus::Ok, 31.5));
+
+        let mut cow = 0;
+        let mut species = std::mem::zeroed::<SpeciesInfo>();
+        assert_eq!(
+            find_species(restored, c"cow".as_ptr(), &mut cow),
+            FarmStatus::Ok
+        );
+        assert_eq!(
+            get_species_info(restored, cow, &mut species),
+            FarmStatus::Ok
+        );
+        assert_eq!(std::ffi::CStr::from_ptr(species.display_name), c"Cow");
+        assert_eq!(species.max_count, 10);
+        let mut cows = 0;
+        assert_eq!(
+            species_animal_count(restored, cow, &mut cows),
+            FarmStatus::Ok
+        );
+        assert_eq!(cows, 1);
+
+        let newer = CString::new(r#"{"version": 99}"#).unwrap();
+        let status = farm_load_json(restored, newer.as_ptr(), std::ptr::null_mut());
+        assert_eq!(status, FarmStatus::NotSupported);
+        let status = farm_load_json(restored, c"[1, 2".as_ptr(), std::ptr::null_mut());
+        assert_eq!(status, FarmStatus::InvalidArgument);
+    }
+    assert_eq!(destroy_farm(restored, None), FarmStatus::Ok);
+}
+
+#[test]
+fn json_snapshots_keep_floats_that_json_cannot_represent() {
+    let farm = new_farm(&sound_functions_map(), DuplicatePolicy::Reject) as *const FarmHandle;
+    let values = [f64::NAN, f64::INFINITY, f64::NEG_INFINITY, -0.5];
+    let keys = [c"nan", c"inf", c"-inf", c"finite"];
+    unsafe {
+        let status = add_animal(
+            farm,
+            c"rex".as_ptr(),
+            sound_animal(c"woof"),
+            std::ptr::null_mut(),
+        );
+        assert_eq!(status, FarmStatus::Ok);
+        for (key, value) in keys.iter().zip(values) {
+            let status = set_animal_attribute_float(farm, c"rex".as_ptr(), key.as_ptr(), value);
+            assert_eq!(status, FarmStatus::Ok);
+        }
+    }
+    let json = save_json(farm);
+    assert_eq!(destroy_farm(farm, None), FarmStatus::Ok);
+
+    let restored = new_farm(&sound_functions_map(), DuplicatePolicy::Reject) as *const FarmHandle;
+    let json = CString::new(json).unwrap();
+    unsafe {
+        let status = farm_load_json(restored, json.as_ptr(), std::ptr::null_mut());
+        assert_eq!(status, FarmStatus::Ok, "{}", last_error());
+        for (key, value) in keys.iter().zip(values) {
+            let mut loaded = 0.0;
+            let status =
+                get_animal_attribute_float(restored, c"rex".as_ptr(), key.as_ptr(), &mut loaded);
+            assert_eq!(status, FarmStatus::Ok);
+            assert_eq!(loaded.to_bits(), value.to_bits());
+        }
+    }
+    assert_eq!(destroy_farm(restored, None), FarmStatus::Ok);
+}
+
+#[test]
+fn json_snapshots_with_labels_the_api_would_refuse_do_not_load() {
+    let farm = new_farm(&sound_functions_map(), DuplicatePolicy::Reject) as *const FarmHandle;
+    for json in [
+        cr#"{"version":1,"animals":[{"name":"rex","state":"woof","attributes":{"owner":{"string":"a\u0000b"}}}]}"#,
+        cr#"{"version":1,"animals":[{"name":"rex","state":"woof","attributes":{"":{"int":1}}}]}"#,
+        cr#"{"version":1,"animals":[{"name":"rex","state":"woof","tags":[""]}]}"#,
+        cr#"{"version":1,"animals":[{"name":"rex","state":"woof","tags":["a\u0000b"]}]}"#,
+    ] {
+        let status = unsafe { farm_load_json(farm, json.as_ptr(), std::ptr::null_mut()) };
+        assert_eq!(status, FarmStatus::InvalidArgument, "{:?}", json);
+    }
+    let mut count = 0;
+    assert_eq!(
+        unsafe { farm_animal_count(farm, &mut count) },
+        FarmStatus::Ok
+    );
+    assert_eq!(count, 0);
+    assert_eq!(destroy_farm(farm, None), FarmStatus::Ok);
+}
+
+fn save_json(farm: *const FarmHandle) -> String {
+    let mut json = std::ptr::null_mut();
+    unsafe {
+        assert_eq!(farm_save_json(farm, &mut json), FarmStatus::Ok);
+        let copy = std::ffi::CStr::from_ptr(json).to_str().unwrap().to_owned();
+        farm_free_string(json);
+        copy
+    }
+}
+
+fn load_binary(data: &[u8]) -> Result<*const FarmHandle, FarmStatus> {
+    let farm = new_farm(&sound_functions_map(), DuplicatePolicy::Reject) as *const FarmHandle;
+    let status = unsafe { farm_load_binary(farm, data.as_ptr(), data.len(), std::ptr::null_mut()) };
+    if status != FarmStatus::Ok {
+        assert_eq!(destroy_farm(farm, None), FarmStatus::Ok);
+        return Err(status);
+    }
+    Ok(farm)
+}
+
+#[test]
+fn binary_snapshot_round_trips_and_skips_unknown_optional_sections() {
+    let farm = snapshot_farm();
+    let data = unsafe {
+        let mut data = std::ptr::null_mut();
+        let mut len = 0;
+        assert_eq!(farm_save_binary(farm, &mut data, &mut len), FarmStatus::Ok);
+        let copy = std::slice::from_raw_parts(data, len).to_vec();
+        farm_free_buffer(data, len);
+        copy
+    };
+
+    let restored = load_binary(&data).unwrap();
+    assert_eq!(save_json(restored), save_json(farm));
+    assert_eq!(destroy_farm(restored, None), FarmStatus::Ok);
+    assert_eq!(destroy_farm(farm, None), FarmStatus::Ok);
+
+    // A newer minor version may append sections this one does not know.
+    let with_section = |kind: u32| {
+        let mut data = data.clone();
+        let section_count = u32::from_le_bytes(data[12..16].try_into().unwrap());
+        data[12..16].copy_from_slice(&(section_count + 1).to_le_bytes());
+        data.extend_from_slice(&kind.to_le_bytes());
+        data.extend_from_slice(&3u64.to_le_bytes());
+        data.extend_from_slice(b"new");
+        data
+    };
+    let restored = load_binary(&with_section(0x40)).unwrap();
+    assert_eq!(destroy_farm(restored, None), FarmStatus::Ok);
+    assert_eq!(
+        load_binary(&with_section(0x8000_0040)),
+        Err(FarmStatus::NotSupported)
+    );
+
+    let mut newer_major = data.clone();
+    newer_major[8..10].copy_from_slice(&2u16.to_le_bytes());
+    assert_eq!(load_binary(&newer_major), Err(FarmStatus::NotSupported));
+    assert_eq!(
+        load_binary(&data[..data.len() - 1]),
+        Err(FarmStatus::InvalidArgument)
+    );
+    assert_eq!(
+        load_binary(b"not a snapshot"),
+        Err(FarmStatus::InvalidArgument)
+    );
+}
+
+// A snapshot of one animal that says "moo", with a long string as its name
+// and `tag_count` tags, all of them that same string.
+fn snapshot_with_repeated_tag(tag_count: u8) -> Vec<u8> {
+    let long = "moo".repeat(20_000);
+    let mut strings = vec![1, 0xe0, 0xd4, 0x03];
+    strings.extend_from_slice(long.as_bytes());
+    let mut tags = vec![1, 0, tag_count];
+    tags.extend(std::iter::repeat_n(0, tag_count as usize));
+
+    let mut data = b"AFRMSNAP".to_vec();
+    data.extend_from_slice(&1u16.to_le_bytes());
+    data.extend_from_slice(&0u16.to_le_bytes());
+    data.extend_from_slice(&6u32.to_le_bytes());
+    let sections = [
+        (1, strings),
+        (2, vec![0]),
+        (3, vec![1, 0, 0]),
+        (4, tags),
+        (5, vec![0]),
+        (6, vec![1, 0, 3, b'm', b'o', b'o']),
+    ];
+    for (kind, payload) in sections {
+        data.extend_from_slice(&(kind as u32 | 1 << 31).to_le_bytes());
+        data.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+        data.extend_from_slice(&payload);
+    }
+    data
+}
+
+#[test]
+fn binary_snapshots_cannot_expand_strings_without_bound() {
+    let farm = load_binary(&snapshot_with_repeated_tag(100)).unwrap();
+    let mut count = 0;
+    assert_eq!(
+        unsafe { farm_animal_count(farm, &mut count) },
+        FarmStatus::Ok
+    );
+    assert_eq!(count, 1);
+    assert_eq!(destroy_farm(farm, None), FarmStatus::Ok);
+
+    // 101 copies of a 60,000 byte string go over a 1 MiB limit.
+    let error = crate::binary_snapshot::decode_within(&snapshot_with_repeated_tag(100), 1 << 20)
+        .unwrap_err();
+    assert_eq!(error.status(), FarmStatus::InvalidArgument);
+    assert!(error.message().contains("too much data"));
+}
+
+#[test]
+fn binary_snapshots_of_long_shared_strings_round_trip() {
+    let farm = new_farm(&sound_functions_map(), DuplicatePolicy::Reject) as *const FarmHandle;
+    let notes = CString::new("moo".repeat(3_500)).unwrap();
+    for n in 0..2000 {
+        let name = CString::new(format!("cow-{}", n)).unwrap();
+        unsafe {
+            let status = add_animal(
+                farm,
+                name.as_ptr(),
+                sound_animal(c"moo"),
+                std::ptr::null_mut(),
+            );
+            assert_eq!(status, FarmStatus::Ok);
+            let status =
+                set_animal_attribute_string(farm, name.as_ptr(), c"notes".as_ptr(), notes.as_ptr());
+            assert_eq!(status, FarmStatus::Ok);
+        }
+    }
+    let data = unsafe {
+        let mut data = std::ptr::null_mut();
+        let mut len = 0;
+        assert_eq!(farm_save_binary(farm, &mut data, &mut len), FarmStatus::Ok);
+        let copy = std::slice::from_raw_parts(data, len).to_vec();
+        farm_free_buffer(data, len);
+        copy
+    };
+    assert!(data.len() < 100_000);
+
+    let restored = load_binary(&data).unwrap();
+    assert_eq!(save_json(restored), save_json(farm));
+    assert_eq!(destroy_farm(restored, None), FarmStatus::Ok);
+    assert_eq!(destroy_farm(farm, None), FarmStatus::Ok);
+}
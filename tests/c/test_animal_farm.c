@@ -0,0 +1,319 @@
+/* Exercises the C API end to end. Built and run by tests/c_api.rs. */
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "animal_farm.h"
+
+#define CHECK(cond)                                                          \
+  do {                                                                       \
+    if (!(cond)) {                                                           \
+      fprintf(stderr, "%s:%d: check failed: %s\n", __FILE__, __LINE__,      \
+              #cond);                                                        \
+      print_last_error();                                                    \
+      exit(1);                                                               \
+    }                                                                        \
+  } while (0)
+
+#define CHECK_OK(call) CHECK((call) == FARM_STATUS_OK)
+
+static void print_last_error(void) {
+  size_t length = farm_last_error_length();
+  if (length == 0) {
+    return;
+  }
+  char *message = malloc(length);
+  if (farm_last_error_message(message, length) == FARM_STATUS_OK) {
+    fprintf(stderr, "last error: %s\n", message);
+  }
+  free(message);
+}
+
+/* Animals are heap-allocated sounds. */
+typedef struct Animal {
+  char sound[32];
+} Animal;
+
+static int spoken;
+static int dropped;
+
+static Animal *new_animal(const char *sound) {
+  Animal *animal = calloc(1, sizeof(Animal));
+  strncpy(animal->sound, sound, sizeof(animal->sound) - 1);
+  return animal;
+}
+
+static void speak(const void *animal, const char *message) {
+  (void)animal;
+  (void)message;
+  spoken++;
+}
+
+static void drop(const void *animal) {
+  free((void *)animal);
+  dropped++;
+}
+
+static char *speak_with_reply(const void *animal, const char *message) {
+  const Animal *a = animal;
+  size_t length = strlen(a->sound) + strlen(message) + 2;
+  char *reply = malloc(length);
+  snprintf(reply, length, "%s %s", a->sound, message);
+  return reply;
+}
+
+static void free_string(char *string) { free(string); }
+
+static char *serialize(const void *animal) {
+  const Animal *a = animal;
+  char *state = malloc(strlen(a->sound) + 1);
+  strcpy(state, a->sound);
+  return state;
+}
+
+static const void *deserialize(const char *name, const char *state) {
+  (void)name;
+  return new_animal(state);
+}
+
+static bool count_animal(void *user_data, AnimalId id, const char *name,
+                         const void *animal) {
+  (void)id;
+  (void)name;
+  (void)animal;
+  (*(size_t *)user_data)++;
+  return true;
+}
+
+static bool is_pig(void *user_data, AnimalId id, const char *name,
+                   const void *animal) {
+  (void)user_data;
+  (void)id;
+  (void)name;
+  return strcmp(((const Animal *)animal)->sound, "oink") == 0;
+}
+
+static FunctionsMap functions_map(void) {
+  FunctionsMap map = {
+      .struct_size = sizeof(FunctionsMap),
+      .version = FUNCTIONS_MAP_VERSION,
+      .speak = speak,
+      .drop = drop,
+      .speak_with_reply = speak_with_reply,
+      .free_reply = free_string,
+      .serialize = serialize,
+      .deserialize = deserialize,
+  };
+  return map;
+}
+
+static const FarmHandle *new_farm(DuplicatePolicy policy) {
+  FunctionsMap map = functions_map();
+  const FarmHandle *farm = NULL;
+  CHECK_OK(create_farm(&map, policy, &farm));
+  CHECK(farm != NULL);
+  return farm;
+}
+
+static void test_animals(void) {
+  const FarmHandle *farm = new_farm(DUPLICATE_POLICY_KEEP_BOTH);
+
+  AddAnimalResult result;
+  CHECK_OK(add_animal(farm, "bessie", new_animal("moo"), &result));
+  CHECK(result.outcome == ADD_OUTCOME_ADDED);
+  AnimalId bessie = result.id;
+  CHECK_OK(add_animal(farm, "bessie", new_animal("moo"), &result));
+  CHECK(result.outcome == ADD_OUTCOME_SUFFIXED && result.suffix == 2);
+
+  FunctionsMap own = {.struct_size = sizeof(FunctionsMap),
+                      .version = FUNCTIONS_MAP_VERSION};
+  CHECK_OK(add_animal_with_functions(farm, "rex", new_animal("woof"), &own,
+                                     NULL));
+
+  const void *animal = NULL;
+  CHECK_OK(get_animal(farm, "rex", &animal));
+  CHECK(strcmp(((const Animal *)animal)->sound, "woof") == 0);
+  CHECK_OK(get_animal_by_id(farm, bessie, &animal));
+  AnimalId id = 0;
+  CHECK_OK(get_animal_id(farm, "bessie", &id));
+  CHECK(id == bessie);
+  CHECK(get_animal(farm, "nobody", &animal) == FARM_STATUS_NOT_FOUND);
+  CHECK(farm_last_error_length() > 0);
+
+  CHECK_OK(native_speak(farm, "bessie", "hello"));
+  CHECK_OK(native_speak_by_id(farm, bessie, "hello"));
+  CHECK(spoken == 2);
+
+  char *reply = NULL;
+  CHECK_OK(native_speak_with_reply(farm, "rex", "hi", &reply));
+  CHECK(strcmp(reply, "woof hi") == 0);
+  farm_free_string(reply);
+
+  CHECK_OK(rename_animal(farm, "bessie#2", "daisy"));
+  CHECK_OK(remove_animal(farm, "daisy", &animal));
+  CHECK(animal == NULL && dropped == 1);
+  CHECK_OK(remove_animal_by_id(farm, bessie, &animal));
+  CHECK(dropped == 2);
+
+  CHECK_OK(destroy_farm(farm, NULL));
+  CHECK(dropped == 3);
+  CHECK(destroy_farm(farm, NULL) == FARM_STATUS_INVALID_HANDLE);
+}
+
+static void test_species_and_enumeration(void) {
+  const FarmHandle *farm = new_farm(DUPLICATE_POLICY_REJECT);
+
+  SpeciesInfo info = {.name = "pig", .default_sound = "oink", .max_count = 2};
+  SpeciesId pig = 0;
+  CHECK_OK(register_species(farm, &info, &pig));
+  SpeciesId found = 99;
+  CHECK_OK(find_species(farm, "pig", &found));
+  CHECK(found == pig);
+  size_t count = 0;
+  CHECK_OK(list_species(farm, NULL, 0, &count));
+  CHECK(count == 1);
+  CHECK_OK(get_species_info(farm, pig, &info));
+  CHECK(strcmp(info.display_name, "pig") == 0);
+
+  CHECK_OK(add_animal_of_species(farm, "wilbur", new_animal("oink"), pig,
+                                 NULL));
+  CHECK_OK(add_animal_of_species(farm, "babe", new_animal("oink"), pig, NULL));
+  CHECK(add_animal_of_species(farm, "napoleon", new_animal("oink"), pig,
+                              NULL) == FARM_STATUS_LIMIT_REACHED);
+  CHECK_OK(add_animal(farm, "bessie", new_animal("moo"), NULL));
+  CHECK_OK(species_animal_count(farm, pig, &count));
+  CHECK(count == 2);
+
+  CHECK_OK(farm_animal_count(farm, &count));
+  CHECK(count == 3);
+
+  FarmIter *iter = NULL;
+  CHECK_OK(farm_iter_begin(farm, &iter));
+  size_t visited = 0;
+  const char *name = NULL;
+  while (farm_iter_next(iter, NULL, &name, NULL) == FARM_STATUS_OK) {
+    visited++;
+  }
+  farm_iter_end(iter);
+  CHECK(visited == 3);
+
+  visited = 0;
+  CHECK_OK(farm_for_each_animal(farm, count_animal, &visited));
+  CHECK(visited == 3);
+
+  spoken = 0;
+  size_t spoke = 0;
+  /* Only the pigs have a default sound to fall back on. */
+  CHECK_OK(native_speak_all(farm, NULL, &spoke));
+  CHECK(spoke == 2 && spoken == 2);
+  CHECK_OK(native_speak_all(farm, "hi", &spoke));
+  CHECK(spoke == 3);
+  CHECK_OK(native_speak_where(farm, "hi", is_pig, NULL, &spoke));
+  CHECK(spoke == 2);
+  CHECK_OK(native_speak_matching(farm, "hi", "b*", &spoke));
+  CHECK(spoke == 2);
+
+  CHECK_OK(destroy_farm(farm, NULL));
+}
+
+static void test_metadata_and_queries(void) {
+  const FarmHandle *farm = new_farm(DUPLICATE_POLICY_REJECT);
+
+  SpeciesInfo info = {.name = "cow"};
+  SpeciesId cow = 0;
+  CHECK_OK(register_species(farm, &info, &cow));
+  AddAnimalResult bessie, daisy;
+  CHECK_OK(add_animal_of_species(farm, "bessie", new_animal("moo"), cow,
+                                 &bessie));
+  CHECK_OK(add_animal_of_species(farm, "daisy", new_animal("moo"), cow,
+                                 &daisy));
+
+  CHECK_OK(add_animal_tag(farm, "bessie", "barn-2"));
+  CHECK_OK(add_animal_tag(farm, "daisy", "barn-1"));
+  bool has_tag = false;
+  CHECK_OK(animal_has_tag(farm, "bessie", "barn-2", &has_tag));
+  CHECK(has_tag);
+  AnimalId ids[4];
+  size_t count = 0;
+  CHECK_OK(farm_animals_with_tag(farm, "barn-2", ids, 4, &count));
+  CHECK(count == 1 && ids[0] == bessie.id);
+  CHECK_OK(remove_animal_tag(farm, "daisy", "barn-1"));
+
+  CHECK_OK(set_animal_attribute_int(farm, "bessie", "age", 5));
+  CHECK_OK(set_animal_attribute_int(farm, "daisy", "age", 2));
+  CHECK_OK(set_animal_attribute_float(farm, "bessie", "weight", 612.5));
+  CHECK_OK(set_animal_attribute_string(farm, "bessie", "breed", "jersey"));
+  CHECK_OK(set_animal_attribute_bool(farm, "bessie", "milked", true));
+
+  int64_t age = 0;
+  CHECK_OK(get_animal_attribute_int(farm, "bessie", "age", &age));
+  CHECK(age == 5);
+  double weight = 0;
+  CHECK_OK(get_animal_attribute_float(farm, "bessie", "weight", &weight));
+  CHECK(weight == 612.5);
+  char *breed = NULL;
+  CHECK_OK(get_animal_attribute_string(farm, "bessie", "breed", &breed));
+  CHECK(strcmp(breed, "jersey") == 0);
+  farm_free_string(breed);
+  bool milked = false;
+  CHECK_OK(get_animal_attribute_bool(farm, "bessie", "milked", &milked));
+  CHECK(milked);
+  AttributeKind kind;
+  CHECK_OK(get_animal_attribute_kind(farm, "bessie", "weight", &kind));
+  CHECK(kind == ATTRIBUTE_KIND_FLOAT);
+  CHECK_OK(remove_animal_attribute(farm, "bessie", "milked"));
+
+  CHECK_OK(farm_query(farm, "species == \"cow\" && age > 3 && tag:barn-2", ids,
+                      4, &count));
+  CHECK(count == 1 && ids[0] == bessie.id);
+  CHECK_OK(farm_query(farm, "age < 3 || name ~ \"b*\"", ids, 4, &count));
+  CHECK(count == 2);
+  CHECK(farm_query(farm, "age >", ids, 4, &count) ==
+        FARM_STATUS_INVALID_ARGUMENT);
+
+  CHECK_OK(destroy_farm(farm, NULL));
+}
+
+static void test_snapshots(void) {
+  const FarmHandle *farm = new_farm(DUPLICATE_POLICY_REJECT);
+  CHECK_OK(add_animal(farm, "bessie", new_animal("moo"), NULL));
+  CHECK_OK(set_animal_attribute_int(farm, "bessie", "age", 5));
+
+  char *json = NULL;
+  CHECK_OK(farm_save_json(farm, &json));
+  uint8_t *data = NULL;
+  size_t len = 0;
+  CHECK_OK(farm_save_binary(farm, &data, &len));
+  CHECK_OK(destroy_farm(farm, NULL));
+
+  const FarmHandle *from_json = new_farm(DUPLICATE_POLICY_REJECT);
+  size_t count = 0;
+  CHECK_OK(farm_load_json(from_json, json, &count));
+  CHECK(count == 1);
+  farm_free_string(json);
+
+  const FarmHandle *from_binary = new_farm(DUPLICATE_POLICY_REJECT);
+  CHECK_OK(farm_load_binary(from_binary, data, len, &count));
+  CHECK(count == 1);
+  farm_free_buffer(data, len);
+
+  const void *animal = NULL;
+  CHECK_OK(get_animal(from_binary, "bessie", &animal));
+  CHECK(strcmp(((const Animal *)animal)->sound, "moo") == 0);
+  int64_t age = 0;
+  CHECK_OK(get_animal_attribute_int(from_json, "bessie", "age", &age));
+  CHECK(age == 5);
+
+  CHECK_OK(destroy_farm(from_json, NULL));
+  CHECK_OK(destroy_farm(from_binary, NULL));
+}
+
+int main(void) {
+  test_animals();
+  test_species_and_enumeration();
+  test_metadata_and_queries();
+  test_snapshots();
+  printf("all C API checks passed\n");
+  return 0;
+}
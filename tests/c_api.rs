@@ -0,0 +1,106 @@
+// Builds the library, then tests/c/test_animal_farm.c against the generated
+// header and the cdylib, and runs it. Skipped where no C compiler is available.
+#![cfg(unix)]
+
+mod common;
+
+use std::{
+    env,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+#[test]
+fn c_program_exercises_the_whole_api() {
+    let manifest_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let lib_dir = common::build_library();
+
+    let flags = [
+        "-I".to_string(),
+        manifest_dir.join("include").display().to_string(),
+        "-L".to_string(),
+        lib_dir.display().to_string(),
+        format!("-Wl,-rpath,{}", lib_dir.display()),
+        "-lanimal_farm".to_string(),
+    ];
+
+    compile_and_run("test_animal_farm", &flags);
+}
+
+// Same program, but with the flags coming from the animal_farm.pc that
+// scripts/package-files.sh writes for this checkout. Skipped where
+// pkg-config is not installed.
+#[test]
+fn pkg_config_file_provides_working_flags() {
+    let manifest_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let lib_dir = common::build_library();
+    let package_dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("package");
+
+    let status = Command::new("sh")
+        .arg(manifest_dir.join("scripts/package-files.sh"))
+        .arg(manifest_dir.join("include"))
+        .arg(&lib_dir)
+        .arg(&package_dir)
+        .status()
+        .unwrap();
+    assert!(status.success(), "scripts/package-files.sh failed");
+
+    let pkg_config = |args: &[&str]| {
+        Command::new("pkg-config")
+            .args(args)
+            .arg("animal_farm")
+            .env("PKG_CONFIG_PATH", &package_dir)
+            .output()
+    };
+
+    let output = match pkg_config(&["--cflags", "--libs"]) {
+        Ok(output) => output,
+        Err(error) => {
+            eprintln!("skipping: could not run `pkg-config`: {}", error);
+            return;
+        }
+    };
+    assert!(
+        output.status.success(),
+        "pkg-config failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let mut flags = String::from_utf8(output.stdout)
+        .unwrap()
+        .split_whitespace()
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+
+    let libdir = pkg_config(&["--variable=libdir"]).unwrap().stdout;
+    let libdir = String::from_utf8(libdir).unwrap();
+    assert_eq!(Path::new(libdir.trim()), lib_dir);
+    flags.push(format!("-Wl,-rpath,{}", libdir.trim()));
+
+    compile_and_run("test_animal_farm_pkg_config", &flags);
+}
+
+fn compile_and_run(name: &str, flags: &[String]) {
+    let manifest_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let program = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let compiler = env::var("CC").unwrap_or_else(|_| "cc".to_string());
+
+    let compiled = Command::new(&compiler)
+        .args(["-std=c99", "-Wall", "-Wextra", "-Werror", "-o"])
+        .arg(&program)
+        .arg(manifest_dir.join("tests/c/test_animal_farm.c"))
+        .args(flags)
+        .status();
+    let compiled = match compiled {
+        Ok(status) => status,
+        Err(error) => {
+            eprintln!("skipping: could not run `{}`: {}", compiler, error);
+            return;
+        }
+    };
+    assert!(compiled.success(), "compiling the C test program failed");
+
+    let output = Command::new(&program).output().unwrap();
+    print!("{}", String::from_utf8_lossy(&output.stdout));
+    eprint!("{}", String::from_utf8_lossy(&output.stderr));
+    assert!(output.status.success(), "the C test program failed");
+}
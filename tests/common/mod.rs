@@ -0,0 +1,32 @@
+// Shared by the integration tests that load the library from outside Rust.
+
+use std::{env, path::PathBuf, process::Command};
+
+// Builds the cdylib and staticlib with the profile the tests were built with
+// and returns the directory they end up in, target/<profile>. `cargo test` only builds the rlib the tests link
+// against, so without this a clean checkout has no library to load and an
+// old checkout loads a stale one.
+pub fn build_library() -> PathBuf {
+    // target/<profile>/deps/<test>-<hash> -> target/<profile>
+    let lib_dir = env::current_exe()
+        .unwrap()
+        .parent()
+        .and_then(|deps| deps.parent())
+        .unwrap()
+        .to_path_buf();
+    let profile = match lib_dir.file_name().unwrap().to_str().unwrap() {
+        "debug" => "dev",
+        profile => profile,
+    };
+
+    let status = Command::new(env!("CARGO"))
+        .args(["build", "--lib", "--profile", profile])
+        .arg("--manifest-path")
+        .arg(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml"))
+        .arg("--target-dir")
+        .arg(lib_dir.parent().unwrap())
+        .status()
+        .unwrap();
+    assert!(status.success(), "building the library failed");
+    lib_dir
+}
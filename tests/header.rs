@@ -0,0 +1,24 @@
+// Checks that the committed header matches the one the build generates from
+// the exported items. Run with `UPDATE_HEADER=1` to overwrite it instead.
+
+use std::{env, fs, path::PathBuf};
+
+const GENERATED: &str = include_str!(concat!(env!("OUT_DIR"), "/animal_farm.h"));
+
+#[test]
+fn committed_header_matches_the_generated_one() {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("include/animal_farm.h");
+    let committed = fs::read_to_string(&path).unwrap_or_default();
+    if committed == GENERATED {
+        return;
+    }
+
+    if env::var_os("UPDATE_HEADER").is_some_and(|update| update == "1") {
+        fs::write(&path, GENERATED).unwrap();
+        return;
+    }
+    panic!(
+        "{} is out of date; run `UPDATE_HEADER=1 cargo test --test header` and commit it",
+        path.display()
+    );
+}
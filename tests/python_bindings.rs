@@ -0,0 +1,35 @@
+// Builds the library, then runs the unit tests of the Python bindings in
+// python/ against the cdylib. Skipped where no Python 3 interpreter is
+// available.
+#![cfg(unix)]
+
+mod common;
+
+use std::{path::PathBuf, process::Command};
+
+#[test]
+fn python_bindings_pass_their_tests() {
+    let manifest_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let lib_dir = common::build_library();
+    let library = if cfg!(target_os = "macos") {
+        "libanimal_farm.dylib"
+    } else {
+        "libanimal_farm.so"
+    };
+
+    let output = Command::new("python3")
+        .args(["-B", "-m", "unittest", "-v", "test_animal_farm"])
+        .current_dir(manifest_dir.join("python"))
+        .env("ANIMAL_FARM_LIB", lib_dir.join(library))
+        .output();
+    let output = match output {
+        Ok(output) => output,
+        Err(error) => {
+            eprintln!("skipping: could not run `python3`: {}", error);
+            return;
+        }
+    };
+    print!("{}", String::from_utf8_lossy(&output.stdout));
+    eprint!("{}", String::from_utf8_lossy(&output.stderr));
+    assert!(output.status.success(), "the Python tests failed");
+}
@@ -0,0 +1,222 @@
+// Drives farms through the safe Rust API, as a Rust consumer of the crate
+// would.
+
+use std::{
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use animal_farm::{AddOutcome, Animal, DuplicatePolicy, Farm, FarmStatus};
+
+#[derive(Default)]
+struct Counters {
+    dropped: AtomicUsize,
+    heard: Mutex<Vec<String>>,
+}
+
+struct Cow {
+    name: &'static str,
+    counters: Arc<Counters>,
+}
+
+impl Animal for Cow {
+    fn speak(&self, message: &str) {
+        let mut heard = self.counters.heard.lock().unwrap();
+        heard.push(format!("{}: {}", self.name, message));
+    }
+}
+
+impl Drop for Cow {
+    fn drop(&mut self) {
+        self.counters.dropped.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+struct Sheep;
+
+impl Animal for Sheep {
+    fn speak(&self, message: &str) {
+        panic!("sheep cannot say {}", message);
+    }
+}
+
+fn cow(name: &'static str, counters: &Arc<Counters>) -> Cow {
+    Cow {
+        name,
+        counters: counters.clone(),
+    }
+}
+
+#[test]
+fn animals_are_stored_looked_up_and_dropped_by_the_farm() {
+    let counters = Arc::new(Counters::default());
+    let farm = Farm::new(DuplicatePolicy::Reject);
+
+    let daisy = farm.add_animal("daisy", cow("daisy", &counters)).unwrap();
+    assert_eq!(daisy.outcome, AddOutcome::Added);
+    farm.add_animal("bella", cow("bella", &counters)).unwrap();
+    assert_eq!(farm.len(), 2);
+    assert_eq!(farm.animal_id("daisy").unwrap(), daisy.id);
+
+    farm.speak("daisy", "moo").unwrap();
+    farm.speak_by_id(daisy.id, "moo again").unwrap();
+    assert_eq!(
+        *counters.heard.lock().unwrap(),
+        ["daisy: moo", "daisy: moo again"]
+    );
+
+    let found = farm.get_animal::<Cow>("daisy").unwrap();
+    assert_eq!(found.name, "daisy");
+    assert_eq!(
+        farm.get_animal_by_id::<Cow>(daisy.id).unwrap().name,
+        "daisy"
+    );
+    let error = farm.get_animal::<Sheep>("daisy").err().unwrap();
+    assert_eq!(error.status(), FarmStatus::InvalidArgument);
+
+    // The reference handed out keeps daisy alive after the farm lets go.
+    farm.rename_animal("daisy", "buttercup").unwrap();
+    farm.remove_animal("buttercup").unwrap();
+    assert_eq!(counters.dropped.load(Ordering::SeqCst), 0);
+    drop(found);
+    assert_eq!(counters.dropped.load(Ordering::SeqCst), 1);
+
+    let error = farm.speak("buttercup", "moo").unwrap_err();
+    assert_eq!(error.status(), FarmStatus::NotFound);
+
+    // A rejected duplicate is dropped rather than leaked.
+    let error = farm
+        .add_animal("bella", cow("bella", &counters))
+        .unwrap_err();
+    assert_eq!(error.status(), FarmStatus::AlreadyExists);
+    assert_eq!(counters.dropped.load(Ordering::SeqCst), 2);
+
+    drop(farm);
+    assert_eq!(counters.dropped.load(Ordering::SeqCst), 3);
+}
+
+#[test]
+fn replaced_animals_are_dropped() {
+    let counters = Arc::new(Counters::default());
+    let farm = Farm::new(DuplicatePolicy::Replace);
+
+    farm.add_animal("daisy", cow("old", &counters)).unwrap();
+    let result = farm.add_animal("daisy", cow("new", &counters)).unwrap();
+
+    assert_eq!(result.outcome, AddOutcome::Replaced);
+    assert!(result.previous_animal.is_null());
+    assert_eq!(counters.dropped.load(Ordering::SeqCst), 1);
+    assert_eq!(farm.get_animal::<Cow>("daisy").unwrap().name, "new");
+}
+
+#[test]
+fn speaking_to_many_animals() {
+    let counters = Arc::new(Counters::default());
+    let farm = Farm::new(DuplicatePolicy::Reject);
+    for name in ["daisy", "dolly", "bella"] {
+        farm.add_animal(name, cow(name, &counters)).unwrap();
+    }
+
+    assert_eq!(farm.speak_all("hello").unwrap(), 3);
+    assert_eq!(farm.speak_matching("hi", "d*").unwrap(), 2);
+    assert_eq!(
+        farm.speak_where("hey", |_, name| name.ends_with('y'))
+            .unwrap(),
+        2
+    );
+
+    let heard = counters.heard.lock().unwrap();
+    assert_eq!(heard.len(), 7);
+    assert!(heard.contains(&"dolly: hey".to_string()));
+    assert!(!heard.contains(&"bella: hi".to_string()));
+}
+
+#[test]
+fn a_panicking_animal_panics_the_caller() {
+    let farm = Farm::new(DuplicatePolicy::Reject);
+    farm.add_animal("shaun", Sheep).unwrap();
+
+    let panic = catch_unwind(AssertUnwindSafe(|| farm.speak("shaun", "baa"))).unwrap_err();
+    assert_eq!(
+        panic.downcast_ref::<String>().unwrap(),
+        "sheep cannot say baa"
+    );
+
+    // The farm is still usable afterwards.
+    assert_eq!(farm.len(), 1);
+    farm.remove_animal("shaun").unwrap();
+}
+
+// Removes itself from the farm while speaking, then checks it is still there
+// to finish speaking.
+struct Leaver {
+    name: String,
+    farm: Arc<Farm>,
+    counters: Arc<Counters>,
+}
+
+impl Animal for Leaver {
+    fn speak(&self, message: &str) {
+        let dropped = self.counters.dropped.load(Ordering::SeqCst);
+        self.farm.remove_animal(&self.name).unwrap();
+        assert_eq!(self.counters.dropped.load(Ordering::SeqCst), dropped);
+        let mut heard = self.counters.heard.lock().unwrap();
+        heard.push(format!("{}: {}", self.name, message));
+    }
+}
+
+impl Drop for Leaver {
+    fn drop(&mut self) {
+        self.counters.dropped.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn an_animal_removed_while_speaking_lives_until_it_is_done() {
+    let counters = Arc::new(Counters::default());
+    let farm = Arc::new(Farm::new(DuplicatePolicy::Reject));
+    let leaver = |name: &str| Leaver {
+        name: name.to_string(),
+        farm: farm.clone(),
+        counters: counters.clone(),
+    };
+
+    farm.add_animal("lassie", leaver("lassie")).unwrap();
+    farm.speak("lassie", "bye").unwrap();
+    assert_eq!(counters.dropped.load(Ordering::SeqCst), 1);
+    assert!(farm.is_empty());
+
+    let id = farm.add_animal("lassie", leaver("lassie")).unwrap().id;
+    farm.speak_by_id(id, "bye again").unwrap();
+    assert_eq!(counters.dropped.load(Ordering::SeqCst), 2);
+
+    farm.add_animal("lassie", leaver("lassie")).unwrap();
+    assert_eq!(farm.speak_all("farewell").unwrap(), 1);
+    assert_eq!(counters.dropped.load(Ordering::SeqCst), 3);
+
+    assert_eq!(
+        *counters.heard.lock().unwrap(),
+        ["lassie: bye", "lassie: bye again", "lassie: farewell"]
+    );
+}
+
+#[test]
+fn names_with_nul_bytes_are_rejected() {
+    let counters = Arc::new(Counters::default());
+    let farm = Farm::new(DuplicatePolicy::Reject);
+
+    let error = farm.add_animal("a\0b", cow("a", &counters)).unwrap_err();
+    assert_eq!(error.status(), FarmStatus::InvalidArgument);
+    assert_eq!(counters.dropped.load(Ordering::SeqCst), 1);
+    assert!(farm.is_empty());
+
+    farm.add_animal("daisy", cow("daisy", &counters)).unwrap();
+    let error = farm.rename_animal("daisy", "dai\0sy").unwrap_err();
+    assert_eq!(error.status(), FarmStatus::InvalidArgument);
+
+    assert_eq!(farm.speak_all("hi").unwrap(), 1);
+    assert_eq!(*counters.heard.lock().unwrap(), ["daisy: hi"]);
+}